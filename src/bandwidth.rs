@@ -0,0 +1,101 @@
+use std::{str::FromStr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use chrono::NaiveTime;
+use simple_error::simple_error;
+
+use crate::GenericError;
+
+/// Bandwidth that was not used can be saved up for at most this long, allowing short bursts
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// A bandwidth limit that only applies during a certain time of the day, e.g. `08:00-22:00=500` for
+/// 500 KiB/s during daytime. Time spans may wrap around midnight, like `22:00-06:00=2000`.
+#[derive(Clone, Debug)]
+pub struct TimeOfDayLimit {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub kib_per_second: u64
+}
+
+impl TimeOfDayLimit {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl FromStr for TimeOfDayLimit {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format_error = || simple_error!("Invalid time-of-day bandwidth limit '{}', expected e.g. '08:00-22:00=500'", s);
+        let (time_span, kib_per_second) = s.split_once('=').ok_or_else(format_error)?;
+        let (start, end) = time_span.split_once('-').ok_or_else(format_error)?;
+        Ok(TimeOfDayLimit {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| format_error())?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| format_error())?,
+            kib_per_second: kib_per_second.trim().parse().map_err(|_| format_error())?
+        })
+    }
+}
+
+/// Limits the rate at which bytes are transferred. Can be shared between several downloads,
+/// which then together stay below the limit.
+pub struct BandwidthLimiter {
+    kib_per_second: Option<u64>,
+    time_of_day_limits: Vec<TimeOfDayLimit>,
+    // The point in time at which the bytes transferred so far are "paid for"
+    next_transfer_time: Mutex<Instant>
+}
+
+impl BandwidthLimiter {
+    /// `kib_per_second` applies whenever none of the `time_of_day_limits` applies. `None` means unlimited.
+    pub fn new(kib_per_second: Option<u64>, time_of_day_limits: Vec<TimeOfDayLimit>) -> Self {
+        BandwidthLimiter { kib_per_second, time_of_day_limits, next_transfer_time: Mutex::new(Instant::now()) }
+    }
+
+    fn current_bytes_per_second(&self) -> Option<u64> {
+        let now = chrono::Local::now().time();
+        self.time_of_day_limits.iter().find(|limit| limit.contains(now))
+            .map(|limit| limit.kib_per_second)
+            .or(self.kib_per_second)
+            .map(|kib_per_second| kib_per_second.max(1) * 1024)
+    }
+
+    /// Accounts for `bytes` transferred bytes, and waits until transferring them is within the limit
+    pub async fn transfer(&self, bytes: usize) {
+        let bytes_per_second = match self.current_bytes_per_second() {
+            Some(bytes_per_second) => bytes_per_second,
+            None => return
+        };
+        let wait_until = {
+            let mut next_transfer_time = self.next_transfer_time.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next_transfer_time).max(now.checked_sub(MAX_BURST).unwrap_or(now));
+            *next_transfer_time = start + Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64);
+            *next_transfer_time
+        };
+        tokio::time::sleep_until(wait_until.into()).await;
+    }
+}
+
+/// The bandwidth limiters a single download is subject to (e.g. a global one and one for the download itself)
+#[derive(Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<Arc<BandwidthLimiter>>
+}
+
+impl Throttle {
+    pub fn new(limiters: Vec<Arc<BandwidthLimiter>>) -> Self {
+        Throttle { limiters }
+    }
+
+    /// Waits until transferring `bytes` bytes is within all limits
+    pub async fn transfer(&self, bytes: usize) {
+        for limiter in &self.limiters {
+            limiter.transfer(bytes).await;
+        }
+    }
+}
@@ -1,16 +1,37 @@
-use std::{fmt::Display, path::{Path, PathBuf}, pin::Pin, sync::Arc};
+use std::{fmt::Display, io::{IsTerminal, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
 
-use futures::{Future, StreamExt, TryFutureExt, stream::FuturesOrdered};
-use tum_autoloader::{GenericError, GenericResult, data::CourseFileResource, download::{download_mp4, download_document},
+use futures::{StreamExt, TryFutureExt, stream::FuturesOrdered};
+use tum_autoloader::{GenericError, GenericResult, data::{CourseFileMetadata, CourseFileResource},
+    download::{DownloadInfo, download_mp4, download_document, download_hls_stream, has_resource_changed, is_transient_error,
+        persist_file, record_hls_livestream, resource_size, temporary_sibling_path},
     moodle::{MoodleCrawlingError, detect_moodle_files, moodle_login},
+    moodle_api::{detect_moodle_files_via_api, moodle_web_service_token},
+    tum_live::{tum_live_login, detect_tum_live_livestreams, detect_tum_live_videos, resolve_tum_live_playlist_url},
+    scheduler::{DownloadFuture, DownloadScheduler},
+    bandwidth::{BandwidthLimiter, Throttle, TimeOfDayLimit},
+    progress::{ProgressObserver, ProgressReport, ProgressTracker, TransferProgress},
+    disk_space::{DiskSpaceGuard, InsufficientDiskSpaceError},
+    retention::{delete_download, videos_to_delete},
+    filters::{DownloadFilter, IgnoreMatcher, known_file_size},
+    recovery::{RecoveryAction, recover_interrupted_file},
+    website::{WebsiteCrawlingError, detect_website_files},
+    naming::{ClaimedPaths, FilenameCollisionSkipped, TargetPathResolver, expand_filename_template, folder_directory, sanitize_path_component,
+        keep_replaced_version, replacement_path},
     http_headers::DEFAULT_HEADERS};
+use reqwest::Url;
 use simple_error::simple_error;
-use tum_autoloader::data::{Course, CourseFileDownload, CourseType, DownloadState};
-use serde_json;
+use tum_autoloader::data::{Course, CourseFile, CourseFileDownload, CourseType, DownloadFailure, DownloadState, FilenameCollisionPolicy,
+    MoodleBackend};
 use tum_autoloader::postprocessing::perform_postprocessing_step;
 use structopt::StructOpt;
-use urlencoding;
-use battery;
+use tokio::sync::mpsc;
+
+/// How many files are checked for changes at the same time
+const MAX_PARALLEL_CHANGE_CHECKS: usize = 8;
+/// How often download progress is redrawn in a terminal
+const LIVE_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+/// How often download progress is logged if the output is not a terminal
+const LOGGED_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(StructOpt)]
 #[structopt(name = "tum-autoloader", about = "Automatically download lecture recordings and files from TUM websites.")]
@@ -31,6 +52,42 @@ struct CommandLineOptions {
     #[structopt(long)]
     discover: bool,
 
+    /// Maximum number of downloads running at the same time. Default: 4.
+    #[structopt(long, default_value="4")]
+    max_parallel_downloads: usize,
+
+    /// Maximum number of downloads from the same host (e.g. Panopto or Moodle) running at the same time. Default: 2.
+    #[structopt(long, default_value="2")]
+    max_parallel_downloads_per_host: usize,
+
+    /// How often a download that failed for a transient reason (e.g. a timeout) is attempted in total,
+    /// over several runs with increasing delays. Default: 5.
+    #[structopt(long, default_value="5")]
+    max_download_attempts: u32,
+
+    /// Maximum total download bandwidth in KiB/s. Default: unlimited.
+    #[structopt(long)]
+    max_bandwidth: Option<u64>,
+
+    /// Maximum bandwidth of a single download in KiB/s. Default: unlimited.
+    #[structopt(long)]
+    max_bandwidth_per_download: Option<u64>,
+
+    /// Maximum total download bandwidth during a time of day, e.g. "08:00-22:00=500" for 500 KiB/s.
+    /// Overrides `--max-bandwidth` during that time. Can be given multiple times.
+    #[structopt(long)]
+    bandwidth_schedule: Vec<TimeOfDayLimit>,
+
+    /// Minimum free disk space in MiB to leave in a download directory. Downloads that would go below it are
+    /// postponed. Can be overridden per course. Default: 1024.
+    #[structopt(long, default_value="1024")]
+    min_free_disk_space: u64,
+
+    /// Only list the videos that would be deleted due to the courses' retention limits
+    /// (`max_keep_days_videos`, `max_keep_videos`), without deleting them
+    #[structopt(long)]
+    retention_dry_run: bool,
+
     /// Print very detailed messages about what the program is doing
     #[structopt(long)]
     verbose: bool
@@ -57,6 +114,15 @@ async fn main() -> GenericResult<()>{
     let mut interval = commandline_options.repeat_interval.map(|interval_minutes|
         tokio::time::interval(tokio::time::Duration::from_secs(interval_minutes * 60)));
 
+    // The global bandwidth limiter is shared by all downloads of all checks
+    let global_bandwidth_limiter = Arc::new(BandwidthLimiter::new(commandline_options.max_bandwidth,
+        commandline_options.bandwidth_schedule.clone()));
+    let download_bandwidth = DownloadBandwidth {
+        global_limiter: global_bandwidth_limiter,
+        max_kib_per_second_per_download: commandline_options.max_bandwidth_per_download
+    };
+    let disk_space = DiskSpace { default_min_free_mib: commandline_options.min_free_disk_space };
+
     if commandline_options.verbose { println!("Loading courses from state file...") }
     let mut courses = match load_courses(&commandline_options.state_file) {
         Ok(courses) => courses,
@@ -66,6 +132,30 @@ async fn main() -> GenericResult<()>{
         }
     };
 
+    // Downloads and postprocessing of a previous run may have been interrupted, e.g. because the process was killed
+    if commandline_options.verbose { println!("Recovering interrupted downloads...") }
+    let recovered_files = match recover_interrupted_files(&mut courses) {
+        Ok(recovered_files) => recovered_files,
+        Err(error) => {
+            if error.downcast_ref::<RecoverFilesError>().is_some() {
+                if let Ok(recover_files_error) = error.downcast::<RecoverFilesError>() {
+                    println!("Errors occured while recovering interrupted downloads.");
+                    for error in recover_files_error.errors {
+                        println!("{}", error);
+                    }
+                    recover_files_error.recovered_files
+                } else { unreachable!() }
+            } else { return Err(error); }
+    }};
+    for (course_index, file_index, action) in recovered_files {
+        let metadata = &courses[course_index].files[file_index].file.metadata;
+        match action {
+            RecoveryAction::ResetToRequested => println!("Download of {} was interrupted, requesting it again.", metadata),
+            RecoveryAction::Finished(path) => println!("Download of {} was found complete at {}.", metadata, path.display()),
+            RecoveryAction::RequeuedPostprocessing => println!("Postprocessing of {} was interrupted, repeating it.", metadata)
+        }
+    }
+
     let mut continue_next_check = true;
     while continue_next_check {
         if let Some(interval) = &mut interval {
@@ -73,10 +163,38 @@ async fn main() -> GenericResult<()>{
             interval.tick().await;
         }
         if commandline_options.verbose { println!("Login to moodle...") }
-        let moodle_auth_cookies = moodle_login(&username, &password).await?;
+        let moodle_auth_cookies = moodle_login(username, password).await?;
+
+        let has_tum_live_courses = courses.iter().any(|course| course.course_type == CourseType::TumLive);
+        let tum_live_auth_cookies = if has_tum_live_courses {
+            if commandline_options.verbose { println!("Login to TUM Live...") }
+            // Without a login, only the TUM Live courses are skipped (see `check_for_updates`)
+            match tum_live_login(username, password).await {
+                Ok(tum_live_auth_cookies) => Some(tum_live_auth_cookies),
+                Err(error) => {
+                    println!("Login to TUM Live failed: {}", error);
+                    None
+                }
+            }
+        } else { None };
+
+        let uses_moodle_api = courses.iter().any(|course| course.course_type == CourseType::Moodle
+            && course.moodle_backend == MoodleBackend::WebServiceApi);
+        let moodle_web_service_token = if uses_moodle_api {
+            if commandline_options.verbose { println!("Obtaining Moodle web service token...") }
+            // Without a token, only the courses using the web service API are skipped (see `check_for_updates`)
+            match moodle_web_service_token(moodle_auth_cookies.clone()).await {
+                Ok(token) => Some(token),
+                Err(error) => {
+                    println!("Obtaining a Moodle web service token failed: {}", error);
+                    None
+                }
+            }
+        } else { None };
 
         if commandline_options.verbose { println!("Checking for updates on course sites...") }
-        let check_for_updates_result = check_for_updates(&mut courses, moodle_auth_cookies.clone()).await;
+        let check_for_updates_result = check_for_updates(&mut courses, moodle_auth_cookies.clone(),
+            moodle_web_service_token.as_deref(), tum_live_auth_cookies.clone()).await;
         let (new_videos_count, new_documents_count) = match check_for_updates_result {
             Ok(count) => count,
             Err(error) => {
@@ -93,23 +211,69 @@ async fn main() -> GenericResult<()>{
 
         println!("{} new videos and {} new documents discovered.", new_videos_count, new_documents_count);
 
+        if !commandline_options.discover {
+            if commandline_options.verbose { println!("Checking downloaded files for changes...") }
+            let changed_files_count = match check_for_changed_files(&mut courses, moodle_auth_cookies.clone()).await {
+                Ok(count) => count,
+                Err(error) => {
+                    if error.downcast_ref::<CheckForChangesError>().is_some() {
+                        if let Ok(check_for_changes_error) = error.downcast::<CheckForChangesError>() {
+                            println!("Errors occured while checking for changed files.");
+                            for error in check_for_changes_error.errors {
+                                println!("{}", error);
+                            }
+                            check_for_changes_error.changed_files_count
+                        } else { unreachable!() }
+                    } else { return Err(error); }
+            }};
+            println!("{} changed files detected.", changed_files_count);
+        }
+
+        // Downloads and livestream recordings share the progress display and must not claim the same paths
+        let progress_tracker = console_progress_tracker();
+        let claimed_paths = ClaimedPaths::new();
+
+        // Livestreams are recorded in the background, while the other files are downloaded and postprocessed
+        let records_livestreams = courses.iter().any(|course|
+            course.course_type == CourseType::TumLive && course.max_livestream_recording_minutes.is_some());
+        let livestream_recordings = match (records_livestreams && !commandline_options.discover, &tum_live_auth_cookies) {
+            (true, Some(tum_live_auth_cookies)) => {
+                if commandline_options.verbose { println!("Starting to record ongoing livestreams...") }
+                Some(start_livestream_recordings(&courses, &download_bandwidth, &progress_tracker, &disk_space, &claimed_paths,
+                    tum_live_auth_cookies.clone()).await?)
+            },
+            _ => None
+        };
+
         if commandline_options.discover {
             if commandline_options.verbose { println!("Setting download states to None (discover mode)...") }
-            for video in courses[0].files.iter_mut() {
+            // Only the files requested by this run's discovery are reset, downloaded files keep their state.
+            // The files the user then requests are not subject to retention.
+            for video in courses.iter_mut().flat_map(|course| course.files.iter_mut())
+                    .filter(|video| video.download_state == DownloadState::Requested) {
                 video.download_state = DownloadState::None;
+                video.auto_requested = false;
             }
         }
 
-        if new_videos_count + new_documents_count > 0 && !commandline_options.discover {
+        // Downloads are due for newly requested files, and for failed downloads to be retried
+        let now = chrono::Utc::now();
+        let has_due_downloads = courses.iter().any(|course| course.files.iter().any(|file| file.is_download_due(now)));
+        if has_due_downloads && !commandline_options.discover {
             if commandline_options.verbose { println!("Processing downloads...") }
-            let downloads_result = process_downloads(&mut courses, 1, moodle_auth_cookies.clone()).await;
+            let state_file_path = &commandline_options.state_file;
+            let downloads_result = process_downloads(&mut courses, commandline_options.max_parallel_downloads,
+                commandline_options.max_parallel_downloads_per_host, commandline_options.max_download_attempts,
+                &download_bandwidth, &progress_tracker, &disk_space, &claimed_paths, moodle_auth_cookies.clone(),
+                tum_live_auth_cookies.clone(), |courses| save_courses(state_file_path, courses)).await;
 
-            let (successful_downloads_indices, failed_downloads) = match downloads_result {
-                Ok(successful_downloads_indices) => (successful_downloads_indices, vec![]),
+            let (successful_downloads_indices, failed_downloads, postponed_downloads, skipped_downloads) = match downloads_result {
+                Ok(successful_downloads_indices) => (successful_downloads_indices, vec![], vec![], vec![]),
                 Err(error) => {
                     if error.downcast_ref::<ProcessDownloadsError>().is_some() {
                         if let Ok(process_downloads_error) = error.downcast::<ProcessDownloadsError>() {
-                            (process_downloads_error.successful_downloads_indices, process_downloads_error.unsuccessful_downloads_indices)
+                            (process_downloads_error.successful_downloads_indices, process_downloads_error.unsuccessful_downloads_indices,
+                                process_downloads_error.postponed_downloads_indices, process_downloads_error.skipped_downloads_indices)
                         } else { unreachable!() }
                     } else { return Err(error); }
                 }
@@ -118,17 +282,40 @@ async fn main() -> GenericResult<()>{
             for (course_index, file_index) in successful_downloads_indices {
                 println!("\tDownloaded {}.", &courses[course_index].files[file_index].file.metadata);
             }
-            if failed_downloads.len() > 0 {
+            if !failed_downloads.is_empty() {
                 println!("Failed to download {} files.", failed_downloads.len());
                 for (course_index, file_index, error) in failed_downloads {
                     println!("\tDownload of {} failed:", &courses[course_index].files[file_index].file.metadata);
                     println!("\t{}", error);
+                    let retry_time = courses[course_index].files[file_index].failure.as_ref().and_then(|failure| failure.next_retry_time);
+                    if let Some(retry_time) = retry_time {
+                        println!("\tRetrying after {}.", retry_time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"));
+                    }
+                }
+            }
+            if !postponed_downloads.is_empty() {
+                println!("Postponed {} downloads.", postponed_downloads.len());
+                for (course_index, file_index, error) in postponed_downloads {
+                    println!("\tDownload of {} postponed:", &courses[course_index].files[file_index].file.metadata);
+                    println!("\t{}", error);
+                }
+            }
+            if !skipped_downloads.is_empty() {
+                println!("Skipped {} downloads.", skipped_downloads.len());
+                for (course_index, file_index, error) in skipped_downloads {
+                    println!("\tDownload of {} skipped:", &courses[course_index].files[file_index].file.metadata);
+                    println!("\t{}", error);
                 }
             }
+        }
 
+        // Postprocessing is pending for new downloads, and possibly for files where it was interrupted before
+        let has_pending_postprocessing = courses.iter().any(|course| course.files.iter()
+            .any(|file| matches!(file.download_state, DownloadState::PostprocessingPending(_))));
+        if has_pending_postprocessing && !commandline_options.discover {
             let state_file_path = commandline_options.state_file.clone();
             let is_verbose = commandline_options.verbose;
-            let mut report_postprocessing_progress = |updated_courses: &_| {
+            let mut report_postprocessing_progress = |updated_courses: &Vec<Course>| {
                 if is_verbose { println!("Postprocessing update: saving courses to state file...") }
                 save_courses(&state_file_path, updated_courses)?;
                 if is_verbose { println!("Refreshing battery state...") }
@@ -142,7 +329,7 @@ async fn main() -> GenericResult<()>{
                     return Ok(false);
                 }
                 if is_verbose { println!("Battery state OK: Continue Postprocessing...") }
-                return Ok(true);
+                Ok(true)
             };
 
             if commandline_options.verbose { println!("Starting postprocessing...") }
@@ -152,6 +339,60 @@ async fn main() -> GenericResult<()>{
                 perform_postprocessing(&mut courses, report_postprocessing_progress)?;
             }
         }
+
+        if let Some(livestream_recordings) = livestream_recordings {
+            if commandline_options.verbose { println!("Waiting for livestream recordings to finish...") }
+            let recorded_livestreams_count = match finish_livestream_recordings(&mut courses, livestream_recordings).await {
+                Ok(count) => count,
+                Err(error) => {
+                    if error.downcast_ref::<RecordLivestreamsError>().is_some() {
+                        if let Ok(record_livestreams_error) = error.downcast::<RecordLivestreamsError>() {
+                            println!("Errors occured while recording livestreams.");
+                            for error in record_livestreams_error.errors {
+                                println!("{}", error);
+                            }
+                            record_livestreams_error.recorded_livestreams_count
+                        } else { unreachable!() }
+                    } else { return Err(error); }
+            }};
+            println!("Recorded {} livestreams.", recorded_livestreams_count);
+        }
+
+        if !commandline_options.discover {
+            if commandline_options.verbose { println!("Enforcing retention limits...") }
+            if commandline_options.retention_dry_run {
+                let expired_videos = courses.iter()
+                    .flat_map(|course| videos_to_delete(course, chrono::Utc::now()).into_iter().map(move |j| &course.files[j]))
+                    .collect::<Vec<_>>();
+                println!("Retention dry run: would delete {} videos.", expired_videos.len());
+                for file in expired_videos {
+                    if let DownloadState::Completed(path) = &file.download_state {
+                        println!("\tWould delete {} ({}).", file.file.metadata, path.display());
+                    }
+                }
+            } else {
+                let deleted_videos_indices = match enforce_retention(&mut courses) {
+                    Ok(deleted_videos_indices) => deleted_videos_indices,
+                    Err(error) => {
+                        if error.downcast_ref::<EnforceRetentionError>().is_some() {
+                            if let Ok(enforce_retention_error) = error.downcast::<EnforceRetentionError>() {
+                                println!("Errors occured while deleting videos due to retention limits.");
+                                for error in enforce_retention_error.errors {
+                                    println!("{}", error);
+                                }
+                                enforce_retention_error.deleted_videos_indices
+                            } else { unreachable!() }
+                        } else { return Err(error); }
+                }};
+                if !deleted_videos_indices.is_empty() {
+                    println!("Deleted {} videos due to retention limits.", deleted_videos_indices.len());
+                    for (course_index, file_index) in deleted_videos_indices {
+                        println!("\tDeleted {}.", &courses[course_index].files[file_index].file.metadata);
+                    }
+                }
+            }
+        }
+
         if commandline_options.verbose { println!("Saving courses to state file...") }
         save_courses(&commandline_options.state_file, &courses)?;
 
@@ -160,6 +401,107 @@ async fn main() -> GenericResult<()>{
     Ok(())
 }
 
+/// Bandwidth limits that apply to downloads
+struct DownloadBandwidth {
+    global_limiter: Arc<BandwidthLimiter>,
+    max_kib_per_second_per_download: Option<u64>
+}
+
+impl DownloadBandwidth {
+    /// Builds the throttle for a new download, subject to the global and its own per-download limit
+    fn throttle(&self) -> Throttle {
+        let download_limiter = BandwidthLimiter::new(self.max_kib_per_second_per_download, vec![]);
+        Throttle::new(vec![self.global_limiter.clone(), Arc::new(download_limiter)])
+    }
+}
+
+/// Minimum free disk space that downloads have to leave
+struct DiskSpace {
+    default_min_free_mib: u64
+}
+
+impl DiskSpace {
+    /// Builds the guard for a download of a file of `course` into `directory`
+    fn guard(&self, course: &Course, directory: PathBuf) -> DiskSpaceGuard {
+        let min_free_mib = course.min_free_disk_space_mib.unwrap_or(self.default_min_free_mib);
+        DiskSpaceGuard::new(directory, min_free_mib * 1024 * 1024)
+    }
+}
+
+/// Shows download progress on stdout: as live lines that are redrawn in place if stdout is a terminal,
+/// otherwise as periodic log lines
+struct ConsoleProgressObserver {
+    live: bool,
+    // How many lines the last live report occupies, s.t. they can be overwritten
+    live_lines_count: Mutex<usize>
+}
+
+fn console_progress_tracker() -> Arc<ProgressTracker> {
+    let live = std::io::stdout().is_terminal();
+    let observer = ConsoleProgressObserver { live, live_lines_count: Mutex::new(0) };
+    ProgressTracker::new(Box::new(observer), if live { LIVE_PROGRESS_INTERVAL } else { LOGGED_PROGRESS_INTERVAL })
+}
+
+impl ProgressObserver for ConsoleProgressObserver {
+    fn report(&self, report: &ProgressReport) {
+        let mut lines = report.files.iter()
+            .filter(|file| file.started && !file.finished)
+            .map(|file| format!("\t{}: {}", file.name, format_transfer_progress(&file.progress)))
+            .collect::<Vec<_>>();
+        let running_downloads_count = lines.len();
+        lines.insert(0, format!("Downloading {} of {} files: {}", running_downloads_count, report.files.len(),
+            format_transfer_progress(&report.aggregate)));
+
+        let mut stdout = std::io::stdout().lock();
+        if self.live {
+            // Move the cursor back up to the previous report and overwrite it, clearing leftover lines below
+            let mut live_lines_count = self.live_lines_count.lock().unwrap();
+            if *live_lines_count > 0 {
+                let _ = write!(stdout, "\x1b[{}A", *live_lines_count);
+            }
+            for line in &lines {
+                let _ = writeln!(stdout, "\x1b[2K{}", line);
+            }
+            let _ = write!(stdout, "\x1b[J");
+            *live_lines_count = lines.len();
+        } else {
+            for line in &lines {
+                let _ = writeln!(stdout, "{}", line);
+            }
+        }
+        let _ = stdout.flush();
+    }
+}
+
+// E.g. "12.3 MiB of 100.0 MiB (12%), 2.1 MiB/s, 0:00:42 remaining"
+fn format_transfer_progress(progress: &TransferProgress) -> String {
+    let mut text = format_bytes(progress.received_bytes as f64);
+    if let Some(total_bytes) = progress.total_bytes {
+        let percentage = (progress.received_bytes * 100).checked_div(total_bytes).unwrap_or(100);
+        text += &format!(" of {} ({}%)", format_bytes(total_bytes as f64), percentage);
+    }
+    text += &format!(", {}/s", format_bytes(progress.bytes_per_second));
+    if let Some(eta) = progress.eta {
+        let seconds = eta.as_secs();
+        text += &format!(", {}:{:02}:{:02} remaining", seconds / 3600, seconds / 60 % 60, seconds % 60);
+    }
+    text
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024.0 {
+        return format!("{:.0} B", bytes);
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 #[derive(Debug)]
 pub struct CheckForUpdatesError {
     pub new_videos_count: u32,
@@ -174,16 +516,60 @@ impl Display for CheckForUpdatesError {
 impl std::error::Error for CheckForUpdatesError {}
 
 async fn check_for_updates(courses: &mut Vec<Course>,
-        moodle_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>) -> GenericResult<(u32, u32)> {
+        moodle_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>,
+        moodle_web_service_token: Option<&str>,
+        tum_live_auth_cookies: Option<Arc<reqwest_cookie_store::CookieStoreMutex>>) -> GenericResult<(u32, u32)> {
     let mut new_videos_count = 0;
     let mut new_documents_count = 0;
     let mut errors = vec![];
 
+    // Used to determine file sizes for the download filters
+    let client = reqwest::Client::builder()
+        .cookie_provider(moodle_auth_cookies.clone())
+        .default_headers((*DEFAULT_HEADERS).clone())
+        .build()?;
+
     for course in courses {
-        match course.course_type {
+        let ignore_matcher = match IgnoreMatcher::new(&course.ignore_rules) {
+            Ok(ignore_matcher) => ignore_matcher,
+            Err(error) => {
+                errors.push(simple_error!("Invalid ignore rules for course {}: {}", course.name, error).into());
+                continue;
+            }
+        };
+        let download_filter = match DownloadFilter::new(&course.download_filters) {
+            Ok(download_filter) => download_filter,
+            Err(error) => {
+                errors.push(simple_error!("Invalid download filters for course {}: {}", course.name, error).into());
+                continue;
+            }
+        };
+        // Ignore rules added later also apply to files that were not downloaded yet
+        for existing_course_file in &mut course.files {
+            let not_downloaded = matches!(existing_course_file.download_state,
+                DownloadState::None | DownloadState::Requested | DownloadState::Failed);
+            if not_downloaded && ignore_matcher.is_ignored(&existing_course_file.file) {
+                existing_course_file.download_state = DownloadState::Ignored;
+            }
+        }
+
+        let mut detected_files = match course.course_type {
+            CourseType::Moodle if course.moodle_backend == MoodleBackend::WebServiceApi => {
+                let token = match moodle_web_service_token {
+                    Some(token) => token,
+                    None => {
+                        errors.push(simple_error!("No Moodle web service token, skipping course {}", course.name).into());
+                        continue;
+                    }
+                };
+                match detect_moodle_files_via_api(&course.url, token).await {
+                    Ok(files) => files,
+                    Err(error) => { errors.push(error); continue; }
+                }
+            },
             CourseType::Moodle => {
                 let detection_result = detect_moodle_files(&course.url, moodle_auth_cookies.clone(), course.max_subpage_depth).await;
-                let mut moodle_files = match detection_result {
+                match detection_result {
                     Ok(files) => files,
                     Err(error) => {
                         // First try the downcast then perform it, to not move the error if we have another error type
@@ -193,51 +579,312 @@ async fn check_for_updates(courses: &mut Vec<Course>,
                                 moodle_crawling_error.successful_detections
                             } else { unreachable!() }
                         } else { return Err(error)}
-                }};
-
-                // Deduplicate found files and update availability information
-                for existing_course_file in &mut course.files {
-                    if let Some((i, _)) = moodle_files.iter().enumerate().find(
-                            |(_, v)| **v == existing_course_file.file) {
-                        moodle_files.remove(i);
-                    } else {
-                        existing_course_file.available = false;
+                }}
+            },
+            CourseType::TumLive => {
+                let tum_live_auth_cookies = match &tum_live_auth_cookies {
+                    Some(tum_live_auth_cookies) => tum_live_auth_cookies.clone(),
+                    None => {
+                        errors.push(simple_error!("Not logged in to TUM Live, skipping course {}", course.name).into());
+                        continue;
                     }
+                };
+                match detect_tum_live_videos(&course.url, tum_live_auth_cookies).await {
+                    Ok(files) => files,
+                    Err(error) => { errors.push(error); continue; }
+                }
+            },
+            CourseType::GenericWebsite => {
+                let detection_result = detect_website_files(&course.url, &course.website_crawl_settings, course.max_subpage_depth).await;
+                match detection_result {
+                    Ok(files) => files,
+                    Err(error) => {
+                        if error.downcast_ref::<WebsiteCrawlingError>().is_some() {
+                            if let Ok(website_crawling_error) = error.downcast::<WebsiteCrawlingError>() {
+                                errors.extend(website_crawling_error.failed_detections);
+                                website_crawling_error.successful_detections
+                            } else { unreachable!() }
+                        } else { errors.push(error); continue; }
+                }}
+            },
+        };
+
+        // Deduplicate found files and update availability information
+        for existing_course_file in &mut course.files {
+            if let Some((i, _)) = detected_files.iter().enumerate().find(
+                    |(_, v)| **v == existing_course_file.file) {
+                detected_files.remove(i);
+            } else {
+                existing_course_file.available = false;
+            }
+        }
+
+        // Add newly found files to `course.files`
+        for course_file in detected_files {
+            let discovery_time = chrono::Utc::now();
+            let ignored = ignore_matcher.is_ignored(&course_file);
+            let mut request_download = !ignored
+                && ((course_file.is_video() && course.auto_download_videos_enabled())
+                    || (course_file.is_document() && course.auto_download_documents_enabled()))
+                && download_filter.accepts(&course_file, discovery_time);
+            if request_download && download_filter.needs_size(&course_file) {
+                // If the size cannot be determined, the file is downloaded anyway
+                let size = match known_file_size(&course_file.metadata) {
+                    Some(size) => Some(size),
+                    None => resource_size(&client, course_file.resource.url()).await.unwrap_or(None)
+                };
+                request_download = download_filter.accepts_size(size);
+            }
+            if course_file.is_document() { new_documents_count  += 1; }
+            else if course_file.is_video() { new_videos_count += 1; }
+
+            let download_state = if ignored { DownloadState::Ignored }
+                else if request_download { DownloadState::Requested }
+                else { DownloadState::None };
+
+            let file_download_data = CourseFileDownload {
+                file: course_file,
+                available: true,
+                download_state,
+                discovery_time,
+                download_time: None,
+                auto_requested: request_download,
+                hls_variant: None,
+                validators: None,
+                previous_versions: vec![],
+                replaced_path: None,
+                failure: None
+            };
+            course.files.push(file_download_data);
+        }
+    }
+    if errors.is_empty() {
+        Ok((new_videos_count, new_documents_count))
+    } else {
+        Err(CheckForUpdatesError { new_videos_count, new_documents_count, errors }.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct CheckForChangesError {
+    pub changed_files_count: u32,
+    pub errors: Vec<GenericError>
+}
+impl Display for CheckForChangesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for CheckForChangesError {}
+
+/// Checks all downloaded files for changes behind their unchanged urls. The current version of a changed file
+/// is kept under a versioned name (e.g. `slides.v1.pdf`) and the file is requested to be downloaded again.
+async fn check_for_changed_files(courses: &mut [Course],
+        moodle_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>) -> GenericResult<u32> {
+    let client = reqwest::Client::builder()
+        .cookie_provider(moodle_auth_cookies.clone())
+        .default_headers((*DEFAULT_HEADERS).clone())
+        .build()?;
+
+    // Only completed downloads can be checked, if validators were recorded for them
+    let checks = courses.iter().enumerate()
+        .flat_map(|(i, course)| course.files.iter().enumerate().filter_map(move |(j, file)| {
+            match (&file.download_state, &file.validators) {
+                (DownloadState::Completed(_), Some(validators)) if file.available =>
+                    Some((i, j, file.file.resource.url().to_owned(), validators.clone())),
+                _ => None
+            }
+        }))
+        .collect::<Vec<_>>();
+    let check_results = futures::stream::iter(checks)
+        .map(|(i, j, url, validators)| {
+            let client = &client;
+            async move { (i, j, has_resource_changed(client, &url, &validators).await) }
+        })
+        .buffer_unordered(MAX_PARALLEL_CHANGE_CHECKS)
+        .collect::<Vec<_>>().await;
+
+    let mut changed_files_count = 0;
+    let mut errors = vec![];
+    for (course_index, file_index, result) in check_results {
+        match result {
+            Ok(true) => {
+                let file = &mut courses[course_index].files[file_index];
+                // The current version is kept in place until the new version has been downloaded
+                if let DownloadState::Completed(path) = &file.download_state {
+                    file.replaced_path = Some(path.clone()).filter(|path| path.exists());
                 }
-                
-                // Add newly found videos to `course.files`
-                for course_file in moodle_files {
-                    let request_download = (course_file.is_video() && course.auto_download_videos_enabled())
-                        || (course_file.is_document() && course.auto_download_documents_enabled());
-                    if course_file.is_document() { new_documents_count  += 1; }
-                    else if course_file.is_video() { new_videos_count += 1; }
-
-                    let file_download_data = CourseFileDownload {
-                        file: course_file,
+                file.download_state = DownloadState::Requested;
+                file.validators = None;
+                changed_files_count += 1;
+            },
+            Ok(false) => {},
+            Err(error) => { errors.push(error); }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(changed_files_count)
+    } else {
+        Err(CheckForChangesError { changed_files_count, errors }.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordLivestreamsError {
+    pub recorded_livestreams_count: u32,
+    pub errors: Vec<GenericError>
+}
+impl Display for RecordLivestreamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for RecordLivestreamsError {}
+
+type LivestreamRecordingResult = GenericResult<(usize, CourseFile, DownloadInfo)>;
+
+/// Livestream recordings running in the background, identified by the index of their course
+struct LivestreamRecordings {
+    recordings: tokio::task::JoinHandle<Vec<LivestreamRecordingResult>>,
+    /// Errors that occured while detecting livestreams
+    errors: Vec<GenericError>
+}
+
+/// Starts recording the ongoing livestreams of all TUM Live courses that have livestream recording enabled.
+/// The recordings run in the background, `finish_livestream_recordings` waits for them to finish.
+async fn start_livestream_recordings(courses: &[Course], download_bandwidth: &DownloadBandwidth,
+        progress_tracker: &Arc<ProgressTracker>, disk_space: &DiskSpace, claimed_paths: &ClaimedPaths,
+        tum_live_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>) -> GenericResult<LivestreamRecordings> {
+    let mut recording_futures = FuturesOrdered::new();
+    let mut errors = vec![];
+
+    let client = reqwest::Client::builder()
+        .cookie_provider(tum_live_auth_cookies.clone())
+        .build()?;
+
+    for (i, course) in courses.iter().enumerate() {
+        let max_recording_minutes = match (&course.course_type, course.max_livestream_recording_minutes) {
+            (CourseType::TumLive, Some(minutes)) => minutes,
+            _ => continue
+        };
+        let file_naming = FileNaming::new(course, claimed_paths);
+        let livestreams = match detect_tum_live_livestreams(&course.url, tum_live_auth_cookies.clone()).await {
+            Ok(livestreams) => livestreams,
+            Err(error) => { errors.push(error); continue; }
+        };
+        // Livestreams that are already known have been recorded (or are being recorded) before,
+        // or their recording was published already
+        let new_livestreams = livestreams.into_iter()
+            .filter(|(livestream, _)| !course.files.iter().any(|f| f.file == *livestream));
+        for (livestream, m3u8_url) in new_livestreams {
+            let resolve_path = file_naming.resolver(course.video_download_directory.clone(),
+                &livestream, chrono::Utc::now(), None);
+            let path = match resolve_path(&hls_stream_filename(&livestream.metadata)) {
+                Ok(path) => path,
+                Err(error) => { errors.push(error); continue; }
+            };
+            let recording_future = record_hls_livestream(client.clone(), m3u8_url, course.hls_variant_policy.clone(),
+                Duration::from_secs(max_recording_minutes * 60), path, download_bandwidth.throttle(),
+                progress_tracker.start_download(livestream.metadata.to_string()),
+                disk_space.guard(course, course.video_download_directory.clone()));
+            recording_futures.push(recording_future.map_ok(move |download_info| (i, livestream, download_info)));
+        }
+    }
+
+    let recordings = tokio::spawn(recording_futures.collect::<Vec<LivestreamRecordingResult>>());
+    Ok(LivestreamRecordings { recordings, errors })
+}
+
+/// Waits for the livestream recordings to finish, and adds the recordings to the courses as `Completed` files
+async fn finish_livestream_recordings(courses: &mut [Course], livestream_recordings: LivestreamRecordings) -> GenericResult<u32> {
+    let LivestreamRecordings { recordings, mut errors } = livestream_recordings;
+    let mut recorded_livestreams_count = 0;
+    for result in recordings.await? {
+        match result {
+            Ok((course_index, livestream, download_info)) => {
+                if let Some(path) = download_info.final_path {
+                    courses[course_index].files.push(CourseFileDownload {
+                        file: livestream,
                         available: true,
-                        download_state: if request_download {DownloadState::Requested} else {DownloadState::None},
+                        download_state: DownloadState::Completed(path),
                         discovery_time: chrono::Utc::now(),
-                        download_time: None
-                    };
-                    course.files.push(file_download_data);
+                        download_time: Some(chrono::Utc::now()),
+                        auto_requested: true,
+                        hls_variant: download_info.hls_variant,
+                        validators: download_info.validators,
+                        previous_versions: vec![],
+                        replaced_path: None,
+                        failure: None
+                    });
+                    recorded_livestreams_count += 1;
                 }
-                
             },
-            CourseType::TumLive => todo!(),
-            CourseType::GenericWebsite => todo!(),
+            Err(error) => { errors.push(error); }
         }
     }
-    if errors.len() == 0 {
-        Ok((new_videos_count, new_documents_count))
+
+    if errors.is_empty() {
+        Ok(recorded_livestreams_count)
     } else {
-        Err(CheckForUpdatesError { new_videos_count, new_documents_count, errors }.into())
+        Err(RecordLivestreamsError { recorded_livestreams_count, errors }.into())
     }
 }
 
+/// Decides where the files of a course are stored
+struct FileNaming {
+    filename_template: Option<String>,
+    collision_policy: FilenameCollisionPolicy,
+    claimed_paths: ClaimedPaths
+}
+
+impl FileNaming {
+    fn new(course: &Course, claimed_paths: &ClaimedPaths) -> Self {
+        FileNaming {
+            filename_template: course.filename_template.clone(),
+            collision_policy: course.filename_collision_policy.clone(),
+            claimed_paths: claimed_paths.clone()
+        }
+    }
+
+    // Files are stored in `directory`, named by the course's filename template if there is one.
+    // Collisions with existing files or other downloads of this run are resolved according to the collision policy.
+    // A new version of a changed file is downloaded next to `replaced_path`, which is only replaced once it is complete.
+    fn resolver(&self, directory: PathBuf, file: &CourseFile, discovery_time: chrono::DateTime<chrono::Utc>,
+        replaced_path: Option<PathBuf>) -> TargetPathResolver
+    {
+        let filename_template = self.filename_template.clone();
+        let collision_policy = self.collision_policy.clone();
+        let claimed_paths = self.claimed_paths.clone();
+        let metadata = file.metadata.clone();
+        let url = file.resource.url().to_owned();
+        Box::new(move |filename| {
+            let relative_path = match &filename_template {
+                Some(template) => expand_filename_template(template, &metadata, filename, discovery_time)?,
+                None => folder_directory(&metadata).unwrap_or_default().join(filename)
+            };
+            let path = claimed_paths.claim(directory.join(relative_path), replaced_path.as_deref(), &collision_policy, &url)?;
+            if let Some(parent_directory) = path.parent() {
+                std::fs::create_dir_all(parent_directory)?;
+            }
+            Ok(if replaced_path.as_ref() == Some(&path) { replacement_path(&path) } else { path })
+        })
+    }
+}
+
+// The url of HLS streams usually ends in a generic "playlist.m3u8", so they are named by their metadata
+fn hls_stream_filename(metadata: &CourseFileMetadata) -> String {
+    format!("{}.mp4", sanitize_path_component(&metadata.to_string()))
+}
+
 #[derive(Debug)]
 pub struct ProcessDownloadsError {
     pub successful_downloads_indices: Vec<(usize, usize)>,
-    pub unsuccessful_downloads_indices: Vec<(usize, usize, GenericError)>
+    pub unsuccessful_downloads_indices: Vec<(usize, usize, GenericError)>,
+    /// Downloads that were not (completely) done due to lack of disk space, they remain requested
+    pub postponed_downloads_indices: Vec<(usize, usize, GenericError)>,
+    /// Downloads that were skipped since their filename collides with another file
+    pub skipped_downloads_indices: Vec<(usize, usize, GenericError)>
 }
 impl Display for ProcessDownloadsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -246,121 +893,283 @@ impl Display for ProcessDownloadsError {
 }
 impl std::error::Error for ProcessDownloadsError {}
 
-async fn process_downloads(courses: &mut Vec<Course>, max_parallel_downloads: usize, 
-        moodle_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>) -> GenericResult<Vec<(usize, usize)>> {
-    let mut download_futures = FuturesOrdered::new();
+// Reports the path chosen by `resolve_path` for the download identified by `key` to `resolved_paths`
+fn report_resolved_path(resolve_path: TargetPathResolver, key: (usize, usize),
+    resolved_paths: mpsc::UnboundedSender<((usize, usize), PathBuf)>) -> TargetPathResolver
+{
+    Box::new(move |filename| {
+        let path = resolve_path(filename)?;
+        // The receiver only stops listening when the downloads are dropped
+        let _ = resolved_paths.send((key, path.clone()));
+        Ok(path)
+    })
+}
+
+/// Downloads all files that are due. `save_state` is called whenever download states change (downloads are started,
+/// their target path is known, or they finish), s.t. an interrupted run can recover from the state file.
+#[allow(clippy::too_many_arguments)]
+async fn process_downloads<F>(courses: &mut [Course], max_parallel_downloads: usize, max_parallel_downloads_per_host: usize,
+        max_download_attempts: u32,
+        download_bandwidth: &DownloadBandwidth, progress_tracker: &Arc<ProgressTracker>, disk_space: &DiskSpace,
+        claimed_paths: &ClaimedPaths, moodle_auth_cookies: Arc<reqwest_cookie_store::CookieStoreMutex>,
+        tum_live_auth_cookies: Option<Arc<reqwest_cookie_store::CookieStoreMutex>>, mut save_state: F)
+        -> GenericResult<Vec<(usize, usize)>>
+    where F: FnMut(&[Course]) -> GenericResult<()>
+{
+    let mut download_scheduler = DownloadScheduler::new(max_parallel_downloads, max_parallel_downloads_per_host);
 
     let client = reqwest::Client::builder()
         .cookie_provider(moodle_auth_cookies.clone())
         .default_headers((*DEFAULT_HEADERS).clone())
         .build()?;
+    let tum_live_client = match tum_live_auth_cookies {
+        Some(tum_live_auth_cookies) => Some(reqwest::Client::builder()
+            .cookie_provider(tum_live_auth_cookies)
+            .default_headers((*DEFAULT_HEADERS).clone())
+            .build()?),
+        None => None
+    };
 
-    // Store course and video indices of all, successful and failed downloads
-    // s.t. after the loop, when the mutable borrow of courses has ended, the download states can be updated.
-    let mut attempted_downloads_indices = vec![];
-    let mut download_results = vec![];
+    // Downloads whose filename is only known once the server responds report their target path through this channel
+    let (resolved_paths_sender, mut resolved_paths) = mpsc::unbounded_channel();
 
     // Iterating over all videos of all courses
+    let now = chrono::Utc::now();
     for (i, course) in courses.iter_mut().enumerate() {
+        // Without a TUM Live login, the course's downloads stay requested until the next run
+        let tum_live_client = match (&course.course_type, &tum_live_client) {
+            (CourseType::TumLive, Some(tum_live_client)) => Some(tum_live_client.clone()),
+            (CourseType::TumLive, None) => continue,
+            _ => None
+        };
+        let file_naming = FileNaming::new(course, claimed_paths);
+        let video_disk_space_guard = disk_space.guard(course, course.video_download_directory.clone());
+        let document_disk_space_guard = disk_space.guard(course, course.file_download_directory.clone());
         for (j, file) in course.files.iter_mut().enumerate() {
 
-            // If the video is requested to be downloaded:
-            if file.download_state == DownloadState::Requested {
-                // Mark download as attempted, construct a download future depending on the video type.
-                attempted_downloads_indices.push((i, j));
-                let download_future: Pin<Box<dyn Future<Output = GenericResult<()>>>> =
-                match &file.file.resource {
+            // If the video is requested to be downloaded (or a failed download should be retried):
+            if file.is_download_due(now) {
+                let progress = progress_tracker.start_download(file.file.metadata.to_string());
+                // Construct a download future depending on the video type.
+                let (url, download_future): (&str, DownloadFuture) = match &file.file.resource {
                     CourseFileResource::Mp4File { url, .. } => {
-                        // For mp4 files: identify target filename from url
-                        match url.split("/").last() {
-                            Some(filename) => {
-                                let decoded_filename = urlencoding::decode(filename).unwrap().to_string();
-                                let path = course.video_download_directory.join(decoded_filename);
-                                // Set download state to running and build the download future
-                                file.download_state = DownloadState::Running(path.clone());
-                                Box::pin(client.get(url).send()
-                                    .err_into::<GenericError>()
-                                    .and_then(move |response| download_mp4(response, path)))
-                            }
-                                // If no filename can be identified: add future indicating this failure
-                            None => { Box::pin(async { Err(simple_error!("URL has no '/'").into()) }) }
-                        }
+                        // For mp4 files: the filename is only known once the server responds, until then only the directory
+                        let directory = course.video_download_directory.clone();
+                        // Set download state to running and build the download future
+                        file.download_state = DownloadState::Running(directory.clone());
+                        let resolve_path = report_resolved_path(file_naming.resolver(directory.clone(), &file.file,
+                            file.discovery_time, file.replaced_path.clone()), (i, j), resolved_paths_sender.clone());
+                        (url, Box::pin(download_mp4(client.clone(), url.clone(), directory, resolve_path, download_bandwidth.throttle(),
+                            progress, video_disk_space_guard.clone())))
                     },
-                    CourseFileResource::HlsStream { .. } => todo!(),
-                    CourseFileResource::Document { url, .. } => {                        
-                        // For documents: identify target filename from url
-                        match url.split("/").last() {
-                            Some(filename) => {
-                                let decoded_filename = urlencoding::decode(filename).unwrap().to_string();
-                                let path = course.file_download_directory.join(decoded_filename);
+                    CourseFileResource::HlsStream { main_m3u8_url } => {
+                        let resolve_path = file_naming.resolver(course.video_download_directory.clone(),
+                            &file.file, file.discovery_time, file.replaced_path.clone());
+                        match resolve_path(&hls_stream_filename(&file.file.metadata)) {
+                            Ok(path) => {
                                 // Set download state to running and build the download future
                                 file.download_state = DownloadState::Running(path.clone());
-                                Box::pin(client.get(url).send()
-                                    .err_into::<GenericError>()
-                                    .and_then(move |response| download_document(response, path)))
-                            }
-                                // If no filename can be identified: add future indicating this failure
-                            None => { Box::pin(async { Err(simple_error!("URL has no '/'").into()) }) }
+                                let variant_policy = course.hls_variant_policy.clone();
+                                let throttle = download_bandwidth.throttle();
+                                let disk_space_guard = video_disk_space_guard.clone();
+                                if let Some(tum_live_client) = tum_live_client.clone() {
+                                    // TUM Live recordings are stored by their watch page, which links a short-lived playlist url
+                                    let watch_page_url = main_m3u8_url.clone();
+                                    (main_m3u8_url, Box::pin(async move {
+                                        let m3u8_url = resolve_tum_live_playlist_url(&tum_live_client, Url::parse(&watch_page_url)?).await?;
+                                        download_hls_stream(tum_live_client, m3u8_url, variant_policy, path, throttle, progress, disk_space_guard).await
+                                    }))
+                                } else {
+                                    (main_m3u8_url, Box::pin(download_hls_stream(client.clone(), main_m3u8_url.clone(), variant_policy, path,
+                                        throttle, progress, disk_space_guard)))
+                                }
+                            },
+                            // If no target path can be determined: add future indicating this failure
+                            Err(error) => { (main_m3u8_url, Box::pin(async { Err(error) })) }
                         }
+                    },
+                    CourseFileResource::Document { url, .. } => {
+                        // For documents: the filename is only known once the server responds, until then only the directory
+                        let directory = course.file_download_directory.clone();
+                        // Set download state to running and build the download future
+                        file.download_state = DownloadState::Running(directory.clone());
+                        let resolve_path = report_resolved_path(file_naming.resolver(directory.clone(), &file.file,
+                            file.discovery_time, file.replaced_path.clone()), (i, j), resolved_paths_sender.clone());
+                        (url, Box::pin(download_document(client.clone(), url.clone(), directory, resolve_path, download_bandwidth.throttle(),
+                            progress, document_disk_space_guard.clone())))
                     }
                 };
-                // Push download future into running queue
-                download_futures.push(download_future);
-            }
-
-            // Ensure that at most `max_parallel_downloads` download run in parallel, store the result
-            if download_futures.len() >= max_parallel_downloads {
-                let result = download_futures.next().await.unwrap();
-                download_results.push(result);
+                // Schedule the download, identified by the course/file index
+                download_scheduler.push((i, j), url, download_future);
             }
         }
     }
 
-    // Make sure that all downloads are finished
-    while download_futures.len() >= max_parallel_downloads {
-        let result = download_futures.next().await.unwrap();
-        download_results.push(result);
-    }
-    drop(download_futures);
+    drop(resolved_paths_sender);
+
+    // Run all downloads, and process target paths and results while they come in
+    let mut download_results = download_scheduler.run();
 
     let mut successful_downloads_indices = vec![];
     let mut unsuccessful_downloads_indices = vec![];
-    // For each result and corresponding course/video index:
-    for (&(course_index, file_index), result) in attempted_downloads_indices.iter().zip(download_results) {
+    let mut postponed_downloads_indices = vec![];
+    let mut skipped_downloads_indices = vec![];
+    loop {
+        // Saved at the start of every iteration: once the downloads are marked running, and after every change
+        save_state(courses)?;
+        // A target path is always received before the result of its download
+        let ((course_index, file_index), result) = tokio::select! {
+            biased;
+            Some(((course_index, file_index), path)) = resolved_paths.recv() => {
+                courses[course_index].files[file_index].download_state = DownloadState::Running(path);
+                continue;
+            },
+            download_result = download_results.next() => match download_result {
+                Some(download_result) => download_result,
+                None => break
+            }
+        };
         match result {
-            Ok(_) => {
+            Ok(download_info) => {
                 let course = &mut courses[course_index];
                 let file = &mut course.files[file_index];
                 // If the download was successful: set state to `Completed`
                 if let DownloadState::Running(ref path) = file.download_state {
                     let needs_postprocessing = !course.video_post_processing_steps.is_empty() && file.file.is_video();
+                    // The downloader may have stored the file under a different path (e.g. a fallback extension)
+                    let path = download_info.final_path.unwrap_or_else(|| path.clone());
+                    // For changed files: the new version replaces the current one only now that it is complete
+                    let path = match keep_replaced_version(file, path) {
+                        Ok(path) => path,
+                        Err(error) => {
+                            file.download_state = DownloadState::Failed;
+                            file.failure = Some(DownloadFailure::after_attempt(file.failure.as_ref(), error.to_string(),
+                                false, max_download_attempts));
+                            unsuccessful_downloads_indices.push((course_index, file_index, error));
+                            continue;
+                        }
+                    };
                     let new_state = if needs_postprocessing { DownloadState::PostprocessingPending } 
-                        else { DownloadState::Completed }(path.clone());
+                        else { DownloadState::Completed }(path);
                     courses[course_index].files[file_index].download_state = new_state;
                     courses[course_index].files[file_index].download_time = Some(chrono::Utc::now());
+                    courses[course_index].files[file_index].hls_variant = download_info.hls_variant;
+                    courses[course_index].files[file_index].validators = download_info.validators;
+                    courses[course_index].files[file_index].failure = None;
                     successful_downloads_indices.push((course_index, file_index))
                 }
             },
             Err(error) => {
-                courses[course_index].files[file_index].download_state = DownloadState::Failed;
+                let file = &mut courses[course_index].files[file_index];
+                if error.downcast_ref::<FilenameCollisionSkipped>().is_some() {
+                    // Downloads skipped due to a filename collision are not failed, they just won't be downloaded
+                    file.download_state = DownloadState::None;
+                    skipped_downloads_indices.push((course_index, file_index, error));
+                    continue;
+                } else if error.downcast_ref::<InsufficientDiskSpaceError>().is_some() {
+                    // Without enough disk space, the download is attempted again in the next run
+                    file.download_state = DownloadState::Requested;
+                    postponed_downloads_indices.push((course_index, file_index, error));
+                    continue;
+                } else {
+                    // Record the failure, which schedules a retry for transient errors
+                    file.download_state = DownloadState::Failed;
+                    file.failure = Some(DownloadFailure::after_attempt(file.failure.as_ref(), error.to_string(),
+                        is_transient_error(error.as_ref()), max_download_attempts));
+                }
                 unsuccessful_downloads_indices.push((course_index, file_index, error))
             }
         }
     }
 
-    if unsuccessful_downloads_indices.len() == 0 {
-        return Ok(successful_downloads_indices)
+    if unsuccessful_downloads_indices.is_empty() && postponed_downloads_indices.is_empty() && skipped_downloads_indices.is_empty() {
+        Ok(successful_downloads_indices)
+    } else {
+        // Return an error with the list of failed and postponed downloads if there are any
+        Err(ProcessDownloadsError { successful_downloads_indices, unsuccessful_downloads_indices, postponed_downloads_indices,
+            skipped_downloads_indices }.into())
+    }
+}
+
+
+#[derive(Debug)]
+pub struct EnforceRetentionError {
+    pub deleted_videos_indices: Vec<(usize, usize)>,
+    pub errors: Vec<GenericError>
+}
+impl Display for EnforceRetentionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for EnforceRetentionError {}
+
+/// Deletes the downloaded videos that exceed their course's `max_keep_days_videos` or `max_keep_videos` limit
+fn enforce_retention(courses: &mut [Course]) -> GenericResult<Vec<(usize, usize)>> {
+    let now = chrono::Utc::now();
+    let mut deleted_videos_indices = vec![];
+    let mut errors = vec![];
+    for (i, course) in courses.iter_mut().enumerate() {
+        for j in videos_to_delete(course, now) {
+            match delete_download(&mut course.files[j]) {
+                Ok(()) => deleted_videos_indices.push((i, j)),
+                Err(error) => errors.push(error)
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(deleted_videos_indices)
     } else {
-        // Return an error with the list of failed downloads if there are any
-        return Err(ProcessDownloadsError { successful_downloads_indices, unsuccessful_downloads_indices }.into())
+        Err(EnforceRetentionError { deleted_videos_indices, errors }.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct RecoverFilesError {
+    pub recovered_files: Vec<(usize, usize, RecoveryAction)>,
+    pub errors: Vec<GenericError>
+}
+impl Display for RecoverFilesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
     }
 }
+impl std::error::Error for RecoverFilesError {}
+
+/// Recovers all files whose download or postprocessing was interrupted in a previous run
+/// (see `recover_interrupted_file`)
+fn recover_interrupted_files(courses: &mut [Course]) -> GenericResult<Vec<(usize, usize, RecoveryAction)>> {
+    let mut recovered_files = vec![];
+    let mut errors = vec![];
+    for (i, course) in courses.iter_mut().enumerate() {
+        let has_postprocessing_steps = !course.video_post_processing_steps.is_empty();
+        for (j, file) in course.files.iter_mut().enumerate() {
+            let download_directory = if file.file.is_video() { &course.video_download_directory }
+                else { &course.file_download_directory };
+            let needs_postprocessing = has_postprocessing_steps && file.file.is_video();
+            match recover_interrupted_file(file, download_directory, needs_postprocessing) {
+                Ok(Some(action)) => recovered_files.push((i, j, action)),
+                Ok(None) => {},
+                Err(error) => errors.push(error)
+            }
+        }
+    }
 
+    if errors.is_empty() {
+        Ok(recovered_files)
+    } else {
+        Err(RecoverFilesError { recovered_files, errors }.into())
+    }
+}
 
-fn save_courses<P>(path: P, courses: &Vec<Course>) -> GenericResult<()>
+// The state file is replaced in one step, s.t. it is never left half-written if the process is killed while saving
+fn save_courses<P>(path: P, courses: &[Course]) -> GenericResult<()>
         where P: AsRef<Path> {
     let json_courses = serde_json::to_string_pretty(&courses)?;
-    std::fs::write(path, json_courses)?;
+    let temporary_path = temporary_sibling_path(path.as_ref(), ".part");
+    std::fs::write(&temporary_path, json_courses)?;
+    persist_file(&temporary_path, path.as_ref())?;
     Ok(())
 }
 
@@ -1,7 +1,8 @@
 use std::{fmt::Display, path::{PathBuf}};
 use serde::{Serialize, Deserialize};
+use reqwest::Url;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum CourseFileMetadata {
     TumLiveStream {
         lecture_title: String,
@@ -11,7 +12,22 @@ pub enum CourseFileMetadata {
     MoodleActivity {
         lecture_title: String,
         section_title: String,
-        activity_title: String
+        activity_title: String,
+        /// Only known when using the `WebServiceApi` backend
+        #[serde(default)]
+        file_size: Option<u64>,
+        /// Only known when using the `WebServiceApi` backend
+        #[serde(default)]
+        time_modified: Option<chrono::DateTime<chrono::Utc>>,
+        /// For files of a folder activity (named `activity_title`): the directory inside the folder, with `/` as
+        /// separator. Files at the top level of a folder have an empty folder path.
+        #[serde(default)]
+        folder_path: Option<String>
+    },
+    /// A link found on a `GenericWebsite` course page
+    WebsiteLink {
+        page_title: String,
+        link_text: String
     }
 }
 
@@ -39,13 +55,54 @@ impl Display for CourseFileMetadata {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CourseFileMetadata::TumLiveStream { lecture_title, video_title: title, .. }
-            | CourseFileMetadata::MoodleActivity { lecture_title, activity_title: title, .. }  => {
+            | CourseFileMetadata::MoodleActivity { lecture_title, activity_title: title, .. }
+            | CourseFileMetadata::WebsiteLink { page_title: lecture_title, link_text: title } => {
                 write!(f, "{} - {}", lecture_title, title)
             }
         }
     }
 }
 
+impl CourseFileResource {
+    pub fn url(&self) -> &str {
+        match self {
+            CourseFileResource::Mp4File { url }
+            | CourseFileResource::Document { url, .. } => url,
+            CourseFileResource::HlsStream { main_m3u8_url } => main_m3u8_url
+        }
+    }
+
+    /// The url that identifies the resource when checking whether it is already known. Moodle serves the same file
+    /// under different urls: scraping finds `pluginfile.php` urls (often with a `forcedownload` query), while the web
+    /// service API lists `webservice/pluginfile.php` urls. Folder files contain the folder's revision
+    /// (`.../mod_folder/content/<revision>/...`), which changes whenever the folder is edited. These differences are
+    /// removed, s.t. switching the Moodle backend of a course or editing a folder does not make its files look new.
+    /// Files are still downloaded from `url` (Moodle serves the current version of a folder file for any revision).
+    pub fn identifying_url(&self) -> String {
+        let url = self.url();
+        match Url::parse(url) {
+            Ok(mut parsed_url) if parsed_url.path().contains("/pluginfile.php/") => {
+                let path = parsed_url.path().replacen("/webservice/pluginfile.php/", "/pluginfile.php/", 1);
+                let mut segments = path.split('/').collect::<Vec<_>>();
+                if let Some(content_index) = segments.windows(2).position(|window| window == ["mod_folder", "content"]) {
+                    if content_index + 3 < segments.len() {
+                        segments.remove(content_index + 2);
+                    }
+                }
+                parsed_url.set_path(&segments.join("/"));
+                parsed_url.set_query(None);
+                parsed_url.to_string()
+            },
+            _ => url.to_owned()
+        }
+    }
+}
+
+/// A hash (FNV-1a) of `url`. Unlike `std`'s hasher, it is stable across builds.
+pub fn url_hash(url: &str) -> u64 {
+    url.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
 impl CourseFile {
     pub fn is_video(&self) -> bool {
         match self.resource {
@@ -67,7 +124,8 @@ impl CourseFile {
 /// For now: Two videos are considered equal if they point to the same resource, ignoring the metadata
 impl PartialEq for CourseFile {
     fn eq(&self, other: &Self) -> bool {
-        self.resource == other.resource
+        std::mem::discriminant(&self.resource) == std::mem::discriminant(&other.resource)
+            && self.resource.identifying_url() == other.resource.identifying_url()
     }
 }
 
@@ -87,7 +145,36 @@ pub enum DownloadState {
     Running(PathBuf),
     PostprocessingPending(PathBuf),
     Completed(PathBuf),
-    Failed
+    Failed,
+    /// The file matches one of the course's ignore rules and is never downloaded
+    Ignored,
+    /// The downloaded file was deleted due to the course's retention limits, and is not downloaded again
+    Deleted
+}
+
+/// How the pages of a `GenericWebsite` course are crawled
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WebsiteCrawlSettings {
+    /// Selectors like `div.content a` or `#slides` for the elements whose links are collected (links inside
+    /// matched elements count as well). Supported are tag names, classes and ids, combined by descendant
+    /// relationships. If empty, all links of a page are collected.
+    #[serde(default)]
+    pub link_selectors: Vec<String>,
+    /// Also follow links to other origins (scheme, host and port) than the course url. Off by default,
+    /// s.t. the crawler does not wander off to external websites.
+    #[serde(default)]
+    pub follow_other_origins: bool
+}
+
+/// How the files of a Moodle course are detected
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug, Default)]
+pub enum MoodleBackend {
+    /// Crawl the course page, which depends on the markup of the Moodle theme
+    #[default]
+    Scraping,
+    /// Use Moodle's web service API (as the Moodle mobile app does). Files found by scraping before are recognized
+    /// (see `CourseFileResource::identifying_url`), so switching the backend does not download them again.
+    WebServiceApi
 }
 
 #[derive(PartialEq, Serialize, Deserialize)]
@@ -97,6 +184,86 @@ pub enum CourseType {
     GenericWebsite
 }
 
+/// Which variant of an HLS master playlist should be downloaded
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug, Default)]
+pub enum HlsVariantPolicy {
+    #[default]
+    Highest,
+    Lowest,
+    /// The best variant whose vertical resolution is at most `height` (e.g. 720 for "max 720p")
+    MaxResolution { height: u32 },
+    ClosestBandwidth { kbps: u64 }
+}
+
+/// A variant stream as announced by `#EXT-X-STREAM-INF` in an HLS master playlist
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
+pub struct HlsVariant {
+    pub url: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    pub codecs: Option<String>
+}
+
+/// HTTP validators of a file at the time it was downloaded, used to detect when the file behind the same url changes
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ResourceValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_length: Option<u64>
+}
+
+/// What to do if a download's target path is already taken, by an existing file or another download
+#[derive(PartialEq, Serialize, Deserialize, Clone, Debug, Default)]
+pub enum FilenameCollisionPolicy {
+    /// Append a counter, e.g. `slides (1).pdf`
+    #[default]
+    NumericSuffix,
+    /// Append a short hash of the file's url, e.g. `slides-1a2b3c4d.pdf`
+    ResourceHashSuffix,
+    /// Do not download the file
+    Skip,
+    Overwrite
+}
+
+/// Files of a course that should never be downloaded. A file is ignored if it matches any of the rules.
+/// Regular expressions are matched case-insensitively.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IgnoreRules {
+    /// Regular expressions matched against the url of a file
+    #[serde(default)]
+    pub url_patterns: Vec<String>,
+    /// Regular expressions matched against the title of a file (e.g. the Moodle activity or TUM Live video title)
+    #[serde(default)]
+    pub title_regexes: Vec<String>,
+    /// Names of Moodle sections whose files are ignored, compared case-insensitively
+    #[serde(default)]
+    pub section_names: Vec<String>
+}
+
+/// Restricts which of the files selected by the `AutoDownloadMode` are requested for download. Files have to
+/// pass all of the filters. Regular expressions are matched case-insensitively.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DownloadFilters {
+    /// If not empty, only files with one of these extensions (e.g. `pdf`) are downloaded. Videos count as `mp4`.
+    #[serde(default)]
+    pub allowed_extensions: Vec<String>,
+    #[serde(default)]
+    pub denied_extensions: Vec<String>,
+    /// Files larger than this (according to a HEAD request) are not downloaded. Files of unknown size are.
+    #[serde(default)]
+    pub max_size_mib: Option<u64>,
+    /// If not empty, only files in a section matching one of these are downloaded
+    #[serde(default)]
+    pub section_regexes: Vec<String>,
+    /// If not empty, only files with a title matching one of these are downloaded
+    #[serde(default)]
+    pub title_regexes: Vec<String>,
+    /// Only files published after this point in time are downloaded: by their modification or stream date if known,
+    /// otherwise by the time they were discovered
+    #[serde(default)]
+    pub discovered_after: Option<chrono::DateTime<chrono::Utc>>
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Course {
     // id: i32,
@@ -111,7 +278,32 @@ pub struct Course {
     pub max_keep_videos: Option<i32>,
     pub video_post_processing_steps: Vec<PostprocessingStep>,
     #[serde(default)]
-    pub max_subpage_depth: i32
+    pub max_subpage_depth: i32,
+    #[serde(default)]
+    pub hls_variant_policy: HlsVariantPolicy,
+    /// For TUM Live courses: if set, ongoing livestreams are recorded, for at most this many minutes
+    #[serde(default)]
+    pub max_livestream_recording_minutes: Option<u64>,
+    /// Path of downloaded files relative to the download directory, e.g. `{section_title}/{activity_title}.{ext}`
+    /// (see `naming::expand_filename_template`). If not set, files are named as suggested by the server.
+    #[serde(default)]
+    pub filename_template: Option<String>,
+    #[serde(default)]
+    pub filename_collision_policy: FilenameCollisionPolicy,
+    /// Downloads are postponed if they would leave less free disk space than this in the download directory.
+    /// If not set, the default given on the command line applies.
+    #[serde(default)]
+    pub min_free_disk_space_mib: Option<u64>,
+    #[serde(default)]
+    pub ignore_rules: IgnoreRules,
+    #[serde(default)]
+    pub download_filters: DownloadFilters,
+    /// For `GenericWebsite` courses
+    #[serde(default)]
+    pub website_crawl_settings: WebsiteCrawlSettings,
+    /// For `Moodle` courses
+    #[serde(default)]
+    pub moodle_backend: MoodleBackend
     /* 
     id
     site url, course name, download directory, re-check interval (seconds), 
@@ -123,17 +315,11 @@ pub struct Course {
 
 impl Course {
     pub fn auto_download_videos_enabled(&self) -> bool {
-        match self.auto_download_mode {
-            AutoDownloadMode::Videos | AutoDownloadMode::All => true,
-            _ => false
-        }
+        matches!(self.auto_download_mode, AutoDownloadMode::Videos | AutoDownloadMode::All)
     }
 
     pub fn auto_download_documents_enabled(&self) -> bool {
-        match self.auto_download_mode {
-            AutoDownloadMode::Documents | AutoDownloadMode::All => true,
-            _ => false
-        }
+        matches!(self.auto_download_mode, AutoDownloadMode::Documents | AutoDownloadMode::All)
     }
 }
 
@@ -145,13 +331,130 @@ pub struct CourseFileDownload<T> {
     pub download_state: DownloadState,
     pub discovery_time: chrono::DateTime<chrono::Utc>,
     pub download_time: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether the download was requested automatically (by the auto download mode or a livestream recording), not by the user.
+    /// Only such downloads are subject to the retention limits.
+    #[serde(default)]
+    pub auto_requested: bool,
+    /// For HLS streams: the variant that was chosen from the master playlist
+    #[serde(default)]
+    pub hls_variant: Option<HlsVariant>,
+    #[serde(default)]
+    pub validators: Option<ResourceValidators>,
+    /// Paths of earlier versions of the file, which were kept when a changed version was downloaded
+    #[serde(default)]
+    pub previous_versions: Vec<PathBuf>,
+    /// For changed files that are downloaded again: the current version, which is kept until the new version is complete
+    #[serde(default)]
+    pub replaced_path: Option<PathBuf>,
+    /// Details about failed download attempts, cleared when a download succeeds
+    #[serde(default)]
+    pub failure: Option<DownloadFailure>,
     /*
     id
     download state (none / requested / running / completed), dowload datetime, file (i.e. the CourseVideo struct), path
     */
 }
 
+impl<T> CourseFileDownload<T> {
+    /// Whether the file should be downloaded now: it was requested, or a failed download is due to be retried
+    pub fn is_download_due(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        match self.download_state {
+            DownloadState::Requested => true,
+            DownloadState::Failed => self.failure.as_ref()
+                .and_then(|failure| failure.next_retry_time)
+                .is_some_and(|next_retry_time| next_retry_time <= now),
+            _ => false
+        }
+    }
+}
+
+/// The first retry of a failed download happens after this delay, which doubles with every further attempt
+const RETRY_BASE_DELAY_MINUTES: i64 = 10;
+const RETRY_MAX_DELAY_MINUTES: i64 = 24 * 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadFailure {
+    pub attempts: u32,
+    pub last_error: String,
+    /// Permanent failures (e.g. 404 Not Found) are not retried
+    pub permanent: bool,
+    /// `None` if the download will not be retried automatically
+    pub next_retry_time: Option<chrono::DateTime<chrono::Utc>>
+}
+
+impl DownloadFailure {
+    /// Records another failed attempt after the `previous` failures. Transient failures are retried with
+    /// exponential backoff, until `max_attempts` attempts have been made.
+    pub fn after_attempt(previous: Option<&DownloadFailure>, last_error: String, transient: bool, max_attempts: u32) -> Self {
+        let attempts = previous.map_or(0, |failure| failure.attempts) + 1;
+        let next_retry_time = if transient && attempts < max_attempts {
+            let delay_minutes = RETRY_BASE_DELAY_MINUTES.saturating_mul(1 << (attempts - 1).min(16)).min(RETRY_MAX_DELAY_MINUTES);
+            Some(chrono::Utc::now() + chrono::Duration::minutes(delay_minutes))
+        } else {
+            None
+        };
+        DownloadFailure { attempts, last_error, permanent: !transient, next_retry_time }
+    }
+}
+
 #[derive(PartialEq, Serialize, Deserialize, Clone)]
 pub enum PostprocessingStep {
     FfmpegReencode { target_fps: u32 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The retry delay measured from now, rounded to minutes
+    fn retry_delay_minutes(failure: &DownloadFailure) -> Option<i64> {
+        failure.next_retry_time.map(|next_retry_time| ((next_retry_time - chrono::Utc::now()).num_seconds() + 30) / 60)
+    }
+
+    #[test]
+    fn transient_failures_are_retried_with_exponential_backoff() {
+        let first = DownloadFailure::after_attempt(None, "timeout".to_owned(), true, 10);
+        assert_eq!(first.attempts, 1);
+        assert!(!first.permanent);
+        assert_eq!(retry_delay_minutes(&first), Some(RETRY_BASE_DELAY_MINUTES));
+
+        let second = DownloadFailure::after_attempt(Some(&first), "timeout".to_owned(), true, 10);
+        assert_eq!(second.attempts, 2);
+        assert_eq!(retry_delay_minutes(&second), Some(2 * RETRY_BASE_DELAY_MINUTES));
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let mut failure = DownloadFailure::after_attempt(None, "timeout".to_owned(), true, 100);
+        for _ in 0..30 {
+            failure = DownloadFailure::after_attempt(Some(&failure), "timeout".to_owned(), true, 100);
+        }
+        assert_eq!(failure.attempts, 31);
+        assert_eq!(retry_delay_minutes(&failure), Some(RETRY_MAX_DELAY_MINUTES));
+    }
+
+    #[test]
+    fn permanent_and_exhausted_failures_are_not_retried() {
+        let permanent = DownloadFailure::after_attempt(None, "404 Not Found".to_owned(), false, 10);
+        assert!(permanent.permanent);
+        assert_eq!(permanent.next_retry_time, None);
+
+        let first = DownloadFailure::after_attempt(None, "timeout".to_owned(), true, 2);
+        let last = DownloadFailure::after_attempt(Some(&first), "connection reset".to_owned(), true, 2);
+        assert_eq!(last.attempts, 2);
+        assert_eq!(last.last_error, "connection reset");
+        assert_eq!(last.next_retry_time, None);
+    }
+
+    #[test]
+    fn identifying_url_ignores_access_path_and_folder_revision() {
+        let document = |url: &str| CourseFileResource::Document { url: url.to_owned(), file_extension: Some("pdf".to_owned()) };
+        let expected = "https://www.moodle.tum.de/pluginfile.php/123/mod_folder/content/sheet.pdf";
+        assert_eq!(document("https://www.moodle.tum.de/pluginfile.php/123/mod_folder/content/4/sheet.pdf").identifying_url(), expected);
+        assert_eq!(document("https://www.moodle.tum.de/webservice/pluginfile.php/123/mod_folder/content/5/sheet.pdf?token=abc")
+            .identifying_url(), expected);
+        assert_eq!(document("https://www.moodle.tum.de/pluginfile.php/123/mod_resource/content/4/sheet.pdf?forcedownload=1")
+            .identifying_url(), "https://www.moodle.tum.de/pluginfile.php/123/mod_resource/content/4/sheet.pdf");
+        assert_eq!(document("https://example.com/sheet.pdf?version=2").identifying_url(), "https://example.com/sheet.pdf?version=2");
+    }
+}
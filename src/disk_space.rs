@@ -0,0 +1,62 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use crate::GenericResult;
+
+const MIB: u64 = 1024 * 1024;
+
+/// A download was not started (or stopped) since it would leave less free disk space than required
+#[derive(Debug)]
+pub struct InsufficientDiskSpaceError {
+    pub directory: PathBuf,
+    pub available_bytes: u64,
+    pub required_bytes: u64,
+    pub min_free_bytes: u64
+}
+
+impl Display for InsufficientDiskSpaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not enough free disk space in {}: {} MiB available, {} MiB needed while keeping {} MiB free",
+            self.directory.display(), self.available_bytes / MIB, self.required_bytes / MIB, self.min_free_bytes / MIB)
+    }
+}
+
+impl std::error::Error for InsufficientDiskSpaceError {}
+
+/// Makes sure that downloads into `directory` leave at least `min_free_bytes` of free disk space.
+/// A guard with `min_free_bytes == 0` never complains.
+#[derive(Clone, Debug, Default)]
+pub struct DiskSpaceGuard {
+    directory: PathBuf,
+    min_free_bytes: u64
+}
+
+impl DiskSpaceGuard {
+    pub fn new(directory: PathBuf, min_free_bytes: u64) -> Self {
+        DiskSpaceGuard { directory, min_free_bytes }
+    }
+
+    /// Checks that `additional_bytes` can still be written without going below the minimum free disk space
+    pub fn check(&self, additional_bytes: u64) -> GenericResult<()> {
+        if self.min_free_bytes == 0 {
+            return Ok(());
+        }
+        let available_bytes = available_space(&self.directory)?;
+        if available_bytes < additional_bytes.saturating_add(self.min_free_bytes) {
+            return Err(InsufficientDiskSpaceError {
+                directory: self.directory.clone(),
+                available_bytes,
+                required_bytes: additional_bytes,
+                min_free_bytes: self.min_free_bytes
+            }.into());
+        }
+        Ok(())
+    }
+}
+
+// The directory may not exist yet, then the free space of its closest existing ancestor counts
+fn available_space(directory: &Path) -> std::io::Result<u64> {
+    let existing_directory = directory.ancestors()
+        .find(|path| !path.as_os_str().is_empty() && path.exists())
+        .unwrap_or_else(|| Path::new("."));
+    fs2::available_space(existing_directory)
+}
@@ -1,99 +1,537 @@
-use std::{fs, io::BufWriter};
-use std::path::{PathBuf};
-use reqwest;
-use std::{fs::File, io::Write, env::temp_dir};
-use futures::{self, TryFutureExt, stream::{FuturesUnordered, StreamExt}};
+use std::io::BufWriter;
+use std::{collections::HashMap, convert::TryInto, ffi::OsString, path::{Path, PathBuf}, time::{Duration, Instant}};
+use reqwest::{self, StatusCode, Url, header};
+use serde::{Serialize, Deserialize};
+use std::{error::Error, fmt::Display, fs::{File, OpenOptions}, io::{ErrorKind, Write}};
+use futures::stream::{FuturesUnordered, StreamExt};
+use simple_error::simple_error;
 
-use crate::GenericResult;
+use crate::{GenericResult, data::{HlsVariant, HlsVariantPolicy, ResourceValidators, url_hash}, naming::{TargetPathResolver, response_filename}, hls::{MediaSegment, decrypt_segment, is_master_playlist,
+    parse_master_playlist, parse_media_playlist, select_variant},
+    postprocessing::remux_to_mp4, bandwidth::Throttle, progress::DownloadProgress, disk_space::DiskSpaceGuard};
 
-pub async fn download_mp4(resp: reqwest::Response, path: PathBuf) -> GenericResult<()> {
-    let mut writer = BufWriter::new(File::create(path)?);
-    let mut stream = resp.bytes_stream();
-    while let Some(chunk) = stream.next().await {
-        writer.write(&chunk?)?;
+/// How many HLS segments are fetched in parallel for a single stream
+const MAX_PARALLEL_SEGMENT_DOWNLOADS: usize = 16;
+/// How often a livestream playlist is re-fetched if it does not specify a target duration
+const DEFAULT_LIVESTREAM_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How many bytes are written between two checks of the free disk space
+const DISK_SPACE_CHECK_INTERVAL_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The server sent fewer (or more) bytes than announced
+#[derive(Debug)]
+pub struct IncompleteDownloadError {
+    pub url: String,
+    pub received_length: u64,
+    pub expected_length: u64
+}
+
+impl Display for IncompleteDownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download of {} incomplete: received {} of {} bytes", self.url, self.received_length, self.expected_length)
     }
+}
+
+impl std::error::Error for IncompleteDownloadError {}
+
+/// Whether a download that failed with `error` may succeed when retried later: timeouts, connection problems,
+/// server errors (5xx) and incomplete transfers are transient, while e.g. 404 Not Found or 403 Forbidden are permanent.
+pub fn is_transient_error(error: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
+            return match reqwest_error.status() {
+                Some(status) => status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS,
+                None => reqwest_error.is_timeout() || reqwest_error.is_connect()
+                    || reqwest_error.is_request() || reqwest_error.is_body() || reqwest_error.is_decode()
+            };
+        }
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            return matches!(io_error.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                | ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe);
+        }
+        if error.is::<IncompleteDownloadError>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Information gathered while downloading a file, to be stored alongside the file's download state
+#[derive(Debug, Default)]
+pub struct DownloadInfo {
+    /// Set if the file was stored at a different path than requested
+    pub final_path: Option<PathBuf>,
+    pub hls_variant: Option<HlsVariant>,
+    pub validators: Option<ResourceValidators>
+}
+
+/// Downloads the mp4 file at `url`. The target path is determined by `resolve_path` from the filename suggested by
+/// the server; interrupted downloads are kept in `download_directory` (see `download_resumable`).
+/// The download is not started, or stopped, if it would violate `disk_space_guard`.
+pub async fn download_mp4(client: reqwest::Client, url: String, download_directory: PathBuf, resolve_path: TargetPathResolver,
+    throttle: Throttle, progress: DownloadProgress, disk_space_guard: DiskSpaceGuard) -> GenericResult<DownloadInfo>
+{
+    download_resumable(&client, &url, &download_directory, resolve_path, &throttle, &progress, &disk_space_guard).await
+}
+
+/// Downloads the document at `url`, like `download_mp4`
+pub async fn download_document(client: reqwest::Client, url: String, download_directory: PathBuf, resolve_path: TargetPathResolver,
+    throttle: Throttle, progress: DownloadProgress, disk_space_guard: DiskSpaceGuard) -> GenericResult<DownloadInfo>
+{
+    download_resumable(&client, &url, &download_directory, resolve_path, &throttle, &progress, &disk_space_guard).await
+}
+
+/// Progress of an interrupted download, stored next to the `.part` file that contains the bytes received so far
+#[derive(Serialize, Deserialize)]
+struct PartialDownload {
+    url: String,
+    expected_length: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<String>
+}
+
+impl PartialDownload {
+    /// The validator to send in an `If-Range` header. Weak ETags are not allowed there.
+    fn if_range_validator(&self) -> Option<&str> {
+        self.etag.as_deref().filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// The hidden files in `download_directory` that an interrupted download of `url` is kept in.
+/// They are named by the url, since the final filename is only known once the server has responded.
+pub fn partial_download_paths(download_directory: &Path, url: &str) -> (PathBuf, PathBuf) {
+    let name = format!(".{:016x}", url_hash(url));
+    (download_directory.join(format!("{}.part", name)), download_directory.join(format!("{}.part.json", name)))
+}
+
+/// A hidden sibling of `path` to write to before the file is complete, e.g. `.slides.pdf.part` for `slides.pdf`
+pub fn temporary_sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+/// The hidden directory next to `path` that the segments of an HLS stream are collected in before they are remuxed
+pub fn segment_directory_path(path: &Path) -> PathBuf {
+    temporary_sibling_path(path, ".segments")
+}
+
+// A directory for intermediate files, which is removed with its contents when dropped
+struct TemporaryDirectory(PathBuf);
+
+impl TemporaryDirectory {
+    // Leftovers from an interrupted download are removed first
+    fn create(path: PathBuf) -> GenericResult<Self> {
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+        Ok(TemporaryDirectory(path))
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TemporaryDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Makes sure the contents of `temporary_path` are on disk, then moves the file to `path` in one step.
+/// This way, a file at `path` is always complete.
+pub fn persist_file(temporary_path: &Path, path: &Path) -> GenericResult<()> {
+    File::open(temporary_path)?.sync_all()?;
+    std::fs::rename(temporary_path, path)?;
     Ok(())
 }
 
-pub async fn download_document(resp: reqwest::Response, path: PathBuf) -> GenericResult<()> {
-    let mut writer = BufWriter::new(File::create(path)?);
+// Downloads `url`, continuing a previously interrupted download if possible. Received bytes are written to a hidden
+// `.part` file in `download_directory`, which is renamed to the path chosen by `resolve_path` when complete.
+// An interrupted download is continued with a `Range` request, guarded by `If-Range` s.t. the download
+// restarts from scratch if the file has changed meanwhile. If the disk runs full, the `.part` file is kept as well.
+async fn download_resumable(client: &reqwest::Client, url: &str, download_directory: &Path, resolve_path: TargetPathResolver,
+    throttle: &Throttle, progress: &DownloadProgress, disk_space_guard: &DiskSpaceGuard) -> GenericResult<DownloadInfo>
+{
+    let (part_path, progress_path) = partial_download_paths(download_directory, url);
+    let previous_progress = std::fs::read(&progress_path).ok()
+        .and_then(|progress| serde_json::from_slice::<PartialDownload>(&progress).ok())
+        .filter(|progress| progress.url == url);
+    let received_length = std::fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+    let mut request = client.get(url);
+    let mut resume_from = 0;
+    if let Some(validator) = previous_progress.as_ref().and_then(PartialDownload::if_range_validator) {
+        if received_length > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", received_length))
+                .header(header::IF_RANGE, validator);
+            resume_from = received_length;
+        }
+    }
+    let mut resp = request.send().await?;
+    // The partial file does not fit the resource (anymore), or the server sent another range than requested: start over
+    let is_other_range = resp.status() == StatusCode::PARTIAL_CONTENT
+        && content_range(&resp).map(|(start, _)| start) != Some(resume_from);
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE || is_other_range {
+        resume_from = 0;
+        resp = client.get(url).send().await?;
+    }
+    let resp = resp.error_for_status()?;
+
+    // The server only continues the download if it answers with the requested range
+    let resumed = resume_from > 0 && resp.status() == StatusCode::PARTIAL_CONTENT
+        && content_range(&resp).map(|(start, _)| start) == Some(resume_from);
+    if !resumed && resp.status() == StatusCode::PARTIAL_CONTENT {
+        return Err(simple_error!("Server sent only part of {} instead of the whole file", url).into());
+    }
+    // `content_length` is the length of the remaining body, also when resuming
+    disk_space_guard.check(resp.content_length().unwrap_or(0))?;
+    // Resolved before any partial files are written, s.t. none are left behind if the download is skipped
+    let path = resolve_path(&response_filename(&resp))?;
+    let (file, partial_download) = if resumed {
+        let expected_length = content_range(&resp).and_then(|(_, total)| total)
+            .or_else(|| previous_progress.as_ref().and_then(|progress| progress.expected_length));
+        let partial_download = PartialDownload { expected_length, ..previous_progress.unwrap() };
+        (OpenOptions::new().append(true).open(&part_path)?, partial_download)
+    } else {
+        let partial_download = PartialDownload {
+            url: url.to_owned(),
+            expected_length: resp.content_length(),
+            etag: header_value(&resp, header::ETAG),
+            last_modified: header_value(&resp, header::LAST_MODIFIED)
+        };
+        (File::create(&part_path)?, partial_download)
+    };
+    std::fs::write(&progress_path, serde_json::to_vec(&partial_download)?)?;
+    progress.start(partial_download.expected_length, if resumed { resume_from } else { 0 });
+
+    let mut writer = BufWriter::new(file);
     let mut stream = resp.bytes_stream();
+    let mut received_length = if resumed { resume_from } else { 0 };
+    let mut unchecked_length = 0;
     while let Some(chunk) = stream.next().await {
-        writer.write(&chunk?)?;
+        let chunk = chunk?;
+        writer.write_all(&chunk)?;
+        progress.add_received_bytes(chunk.len() as u64);
+        received_length += chunk.len() as u64;
+        unchecked_length += chunk.len() as u64;
+        if unchecked_length >= DISK_SPACE_CHECK_INTERVAL_BYTES {
+            // Other programs may use up disk space as well while downloading
+            let remaining_length = partial_download.expected_length.map_or(0, |expected| expected.saturating_sub(received_length));
+            disk_space_guard.check(remaining_length)?;
+            unchecked_length = 0;
+        }
+        throttle.transfer(chunk.len()).await;
     }
-    Ok(())
+    writer.flush()?;
+    drop(writer);
+
+    // A body shorter than announced (e.g. after a dropped connection) is an incomplete download. The `.part`
+    // file is kept s.t. the download can be resumed, unless it is longer than expected and thus unusable.
+    let received_length = std::fs::metadata(&part_path)?.len();
+    if let Some(expected_length) = partial_download.expected_length {
+        if received_length != expected_length {
+            if received_length > expected_length {
+                std::fs::remove_file(&part_path)?;
+                std::fs::remove_file(&progress_path)?;
+            }
+            return Err(IncompleteDownloadError { url: url.to_owned(), received_length, expected_length }.into());
+        }
+    }
+
+    persist_file(&part_path, &path)?;
+    std::fs::remove_file(&progress_path)?;
+    let validators = ResourceValidators {
+        etag: partial_download.etag,
+        last_modified: partial_download.last_modified,
+        content_length: partial_download.expected_length
+    };
+    Ok(DownloadInfo { final_path: Some(path), validators: Some(validators), ..DownloadInfo::default() })
 }
 
-async fn process_lecture(lecture_page_url: &str) -> Result<(), reqwest::Error> {
-    let body = reqwest::get(lecture_page_url).await?
-        .text().await?;
+/// Checks whether the file at `url` has changed since it was downloaded with `validators`, using a conditional
+/// HEAD request. Since servers may ignore the conditions, the validators of the response are compared as well.
+pub async fn has_resource_changed(client: &reqwest::Client, url: &str, validators: &ResourceValidators) -> GenericResult<bool> {
+    let mut request = client.head(url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = request.send().await?;
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok(false);
+    }
+    let resp = resp.error_for_status()?;
 
-    let video_page_url = "";
-    let body = reqwest::get(lecture_page_url).await?
-    .text().await?;
-    Ok(())
+    // Only validators known both before and now can indicate a change
+    fn differs<T: PartialEq>(before: &Option<T>, now: &Option<T>) -> bool {
+        matches!((before, now), (Some(before), Some(now)) if before != now)
+    }
+    // `Response::content_length` is always 0 for HEAD requests, so the header is read directly
+    let content_length = header_value(&resp, header::CONTENT_LENGTH).and_then(|length| length.parse().ok());
+    Ok(differs(&validators.etag, &header_value(&resp, header::ETAG))
+        || differs(&validators.last_modified, &header_value(&resp, header::LAST_MODIFIED))
+        || differs(&validators.content_length, &content_length))
 }
 
-fn is_no_comment(line: &&str) -> bool {
-    line.chars().next() != Some('#')
+/// Determines the size of the file at `url` with a HEAD request. Returns `None` if the server does not tell.
+pub async fn resource_size(client: &reqwest::Client, url: &str) -> GenericResult<Option<u64>> {
+    let resp = client.head(url).send().await?.error_for_status()?;
+    Ok(header_value(&resp, header::CONTENT_LENGTH).and_then(|length| length.parse().ok()))
 }
 
+fn header_value(resp: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    resp.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_owned)
+}
 
-async fn download_video(m3u8_url: &str, out_path: &str) -> Result<(), reqwest::Error> {
-    let chunklist_id = reqwest::get(m3u8_url).await?.text().await?
-        .lines().filter(is_no_comment).next().unwrap().to_owned();
+// Parses a `Content-Range: bytes <start>-<end>/<total>` header into start and (if known) total length
+fn content_range(resp: &reqwest::Response) -> Option<(u64, Option<u64>)> {
+    let content_range = header_value(resp, header::CONTENT_RANGE)?;
+    let (range, total) = content_range.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.trim().parse().ok()?;
+    Some((start, total.trim().parse().ok()))
+}
 
-    let mp4_base_url = m3u8_url.rmatch_indices("/").next()
-        .map(|(last_slash_index, _)| &m3u8_url[..last_slash_index])
-        .unwrap();
+/// Downloads the HLS stream behind `m3u8_url` and remuxes the concatenated segments into an MP4 file at `path`.
+/// `m3u8_url` may point to a master playlist (then a variant is chosen according to `variant_policy`)
+/// or directly to a media playlist. If remuxing fails, the transport stream is kept with a `.ts` extension instead.
+/// The segments are collected next to `path` (see `segment_directory_path`), s.t. they count towards `disk_space_guard`.
+/// The download is stopped if it would violate `disk_space_guard`, judging by the size of the segments so far.
+pub async fn download_hls_stream(client: reqwest::Client, m3u8_url: String, variant_policy: HlsVariantPolicy,
+    path: PathBuf, throttle: Throttle, progress: DownloadProgress, disk_space_guard: DiskSpaceGuard) -> GenericResult<DownloadInfo>
+{
+    disk_space_guard.check(0)?;
+    progress.start(None, 0);
+    let (media_playlist_url, media_playlist, hls_variant) = resolve_media_playlist(&client, &m3u8_url, &variant_policy).await?;
 
-    let m3u8_content = reqwest::get([mp4_base_url, &chunklist_id].join("/")).await?.text().await?;
-    let m3u8_ts_lines = m3u8_content.lines()
-        .filter(is_no_comment)
-        .collect::<Vec<_>>();
+    let segments = parse_media_playlist(&media_playlist_url, &media_playlist)?.segments;
+    if segments.is_empty() {
+        return Err(simple_error!("HLS playlist {} contains no segments", media_playlist_url).into());
+    }
 
-    let ts_dir = temp_dir().join(chunklist_id);
-    fs::create_dir(&ts_dir).unwrap();
+    // The segment directory is removed when `segment_dir` is dropped
+    let segment_dir = TemporaryDirectory::create(segment_directory_path(&path))?;
+    let mut keys = HashMap::new();
+    fetch_segment_keys(&client, &segments, &mut keys).await?;
+    let stream_bytes = download_segments(&client, &segments, &keys, segment_dir.path(), &throttle, &progress,
+        &disk_space_guard).await?;
+    let ts_path = segment_dir.path().join("stream.ts");
+    concatenate_segments(segment_dir.path(), segments.len(), &ts_path)?;
 
-    let mut download_futures = FuturesUnordered::new();
+    // Remuxing writes a second copy of the stream
+    disk_space_guard.check(stream_bytes)?;
+    let final_path = tokio::task::spawn_blocking(move || remux_or_keep_ts(&ts_path, &path)).await??;
+    Ok(DownloadInfo { final_path: Some(final_path), hls_variant, validators: None })
+}
+
+/// Records the ongoing HLS livestream behind `m3u8_url` into an MP4 file at `path` (like `download_hls_stream`).
+/// The media playlist is polled for new segments until the stream ends (`#EXT-X-ENDLIST`),
+/// the playlist becomes unavailable, or `max_duration` has passed. If the free disk space falls below the minimum
+/// of `disk_space_guard`, the recording stops early and what was recorded so far is kept. Segments that cannot be
+/// fetched are skipped, s.t. a single network error does not lose the whole recording.
+#[allow(clippy::too_many_arguments)]
+pub async fn record_hls_livestream(client: reqwest::Client, m3u8_url: String, variant_policy: HlsVariantPolicy,
+    max_duration: Duration, path: PathBuf, throttle: Throttle, progress: DownloadProgress, disk_space_guard: DiskSpaceGuard)
+    -> GenericResult<DownloadInfo>
+{
+    disk_space_guard.check(0)?;
+    let recording_start = Instant::now();
+    progress.start(None, 0);
+    let (media_playlist_url, media_playlist, hls_variant) = resolve_media_playlist(&client, &m3u8_url, &variant_policy).await?;
+
+    let segment_dir = TemporaryDirectory::create(segment_directory_path(&path))?;
+    let ts_path = segment_dir.path().join("stream.ts");
+    let mut writer = BufWriter::new(File::create(&ts_path)?);
+    let mut keys = HashMap::new();
+    let mut next_sequence_number = None;
+    let mut recorded_segments_count = 0;
+    let mut media_playlist = parse_media_playlist(&media_playlist_url, &media_playlist)?;
+    loop {
+        // Segments stay in a live playlist for a while, so only append those not recorded yet
+        let new_segments = media_playlist.segments.iter()
+            .filter(|segment| next_sequence_number.is_none_or(|next| segment.sequence_number >= next))
+            .cloned().collect::<Vec<_>>();
+        let mut disk_full = false;
+        for segment in new_segments {
+            if disk_space_guard.check(0).is_err() {
+                disk_full = true;
+                break;
+            }
+            let data = async {
+                fetch_segment_keys(&client, std::slice::from_ref(&segment), &mut keys).await?;
+                fetch_segment(&client, &segment, &keys, &throttle, &progress).await
+            }.await;
+            // A missing segment only leaves a short gap in the recording
+            if let Ok(data) = data {
+                writer.write_all(&data)?;
+                recorded_segments_count += 1;
+            }
+            next_sequence_number = Some(segment.sequence_number + 1);
+        }
+
+        if disk_full || media_playlist.ended || recording_start.elapsed() >= max_duration {
+            break;
+        }
+        let poll_interval = media_playlist.target_duration.map_or(DEFAULT_LIVESTREAM_POLL_INTERVAL, Duration::from_secs);
+        tokio::time::sleep(poll_interval.min(max_duration.saturating_sub(recording_start.elapsed()))).await;
 
-    for (i, line) in m3u8_ts_lines.iter().enumerate() {
-        let ts_url = [mp4_base_url, line].join("/");
-
-        let path = ts_dir.join(i.to_string());
-        let download_future = reqwest::get(ts_url)
-            .and_then(|response| response.bytes())
-            .and_then(move |ts_contents| {
-                std::fs::write(path, ts_contents)
-                    .expect("Couldn't write to file...");
-                futures::future::ok::<(), reqwest::Error>(())
-            })
-            .or_else(|e| {
-                println!("Error: {:?}", e);
-                futures::future::ok::<(), reqwest::Error>(())
-            });
-        download_futures.push(download_future);
-
-        if download_futures.len() >= 150 {
-            download_futures.next().await;
+        // When the stream is over, the playlist often just disappears: keep what was recorded so far
+        let refreshed_playlist = async {
+            client.get(media_playlist_url.clone()).send().await?
+                .error_for_status()?.text().await
+        }.await;
+        match refreshed_playlist.map(|playlist| parse_media_playlist(&media_playlist_url, &playlist)) {
+            Ok(Ok(refreshed_playlist)) => { media_playlist = refreshed_playlist; },
+            _ => break
         }
-        // let ts_contents = reqwest::get(ts_url).await?.bytes().await?;
     }
-    // download_futures.;
-    while let Some(_) = download_futures.next().await {}
-    dbg!("Wrote to file!");
+    writer.flush()?;
+    drop(writer);
 
-    let mut mp4_out_file = std::fs::File::create(out_path)
-        .expect("Couldn't create out file.");
+    if recorded_segments_count == 0 {
+        return Err(simple_error!("HLS livestream {} contained no segments to record", media_playlist_url).into());
+    }
+    // Remuxing writes a second copy of the recording. Without enough space, the transport stream is kept instead.
+    if disk_space_guard.check(std::fs::metadata(&ts_path)?.len()).is_err() {
+        let fallback_path = path.with_extension("ts");
+        persist_file(&ts_path, &fallback_path)?;
+        return Ok(DownloadInfo { final_path: Some(fallback_path), hls_variant, validators: None });
+    }
+    let final_path = tokio::task::spawn_blocking(move || remux_or_keep_ts(&ts_path, &path)).await??;
+    Ok(DownloadInfo { final_path: Some(final_path), hls_variant, validators: None })
+}
 
-    for i in 0..m3u8_ts_lines.len() {
-        let bytes = std::fs::read(ts_dir.join(i.to_string()))
-            .expect("Couldn't read ts file...");
-        mp4_out_file.write(&bytes)
-            .expect("Couldn't write bytes to file...");
+// Remuxes the transport stream at `ts_path` into an MP4 file at `mp4_path`. If that fails, the transport stream
+// is moved next to `mp4_path` with a `.ts` extension (it is on the same file system). Returns the path of the file that was kept.
+fn remux_or_keep_ts(ts_path: &Path, mp4_path: &Path) -> GenericResult<PathBuf> {
+    let temporary_mp4_path = temporary_sibling_path(mp4_path, ".part");
+    if remux_to_mp4(ts_path, &temporary_mp4_path).is_ok() {
+        persist_file(&temporary_mp4_path, mp4_path)?;
+        return Ok(mp4_path.to_owned());
+    }
+    if temporary_mp4_path.exists() {
+        std::fs::remove_file(&temporary_mp4_path)?;
     }
+    let fallback_path = mp4_path.with_extension("ts");
+    persist_file(ts_path, &fallback_path)?;
+    Ok(fallback_path)
+}
 
-    dbg!("CATed!");
+// Fetches the playlist at `m3u8_url`. If it is a master playlist, selects a variant and follows it to its media playlist.
+async fn resolve_media_playlist(client: &reqwest::Client, m3u8_url: &str, variant_policy: &HlsVariantPolicy)
+    -> GenericResult<(Url, String, Option<HlsVariant>)>
+{
+    let playlist_url = Url::parse(m3u8_url)?;
+    let playlist = client.get(playlist_url.clone()).send().await?
+        .error_for_status()?.text().await?;
+    if !is_master_playlist(&playlist) {
+        return Ok((playlist_url, playlist, None));
+    }
+
+    let variants = parse_master_playlist(&playlist_url, &playlist)?;
+    let variant = select_variant(&variants, variant_policy)
+        .ok_or(simple_error!("HLS master playlist {} contains no variants", playlist_url))?;
+    let media_playlist_url = Url::parse(&variant.url)?;
+    let media_playlist = client.get(media_playlist_url.clone()).send().await?
+        .error_for_status()?.text().await?;
+    Ok((media_playlist_url, media_playlist, Some(variant.clone())))
+}
+
+// Fetches all distinct AES-128 keys referenced by `segments` (there may be several due to key rotation)
+// that are not yet contained in `keys`
+async fn fetch_segment_keys(client: &reqwest::Client, segments: &[MediaSegment], keys: &mut HashMap<Url, [u8; 16]>)
+    -> GenericResult<()>
+{
+    for key in segments.iter().filter_map(|segment| segment.key.as_ref()) {
+        if keys.contains_key(&key.uri) {
+            continue;
+        }
+        let key_bytes = client.get(key.uri.clone()).send().await?
+            .error_for_status()?.bytes().await?;
+        let key_bytes: [u8; 16] = key_bytes.as_ref().try_into()
+            .map_err(|_| simple_error!("HLS key {} does not have 16 bytes", key.uri))?;
+        keys.insert(key.uri.clone(), key_bytes);
+    }
+    Ok(())
+}
+
+// Downloads a single segment and decrypts it if necessary. The segment's key must already be contained in `keys`.
+async fn fetch_segment(client: &reqwest::Client, segment: &MediaSegment, keys: &HashMap<Url, [u8; 16]>, throttle: &Throttle,
+    progress: &DownloadProgress) -> GenericResult<Vec<u8>>
+{
+    let resp = client.get(segment.url.clone()).send().await?.error_for_status()?;
+    let mut data = Vec::with_capacity(resp.content_length().unwrap_or(0) as usize);
+    let mut stream = resp.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        data.extend_from_slice(&chunk);
+        progress.add_received_bytes(chunk.len() as u64);
+        throttle.transfer(chunk.len()).await;
+    }
+    match &segment.key {
+        Some(key) => decrypt_segment(&data, &keys[&key.uri], &segment.iv()),
+        None => Ok(data)
+    }
+}
+
+// Downloads (and if necessary decrypts) all segments into `segment_dir`, named by their index, and returns their total size.
+// At most `MAX_PARALLEL_SEGMENT_DOWNLOADS` run in parallel. Since the playlist does not state segment sizes,
+// the total size (reported to `progress` and checked against `disk_space_guard`) is extrapolated from the segments
+// downloaded so far.
+async fn download_segments(client: &reqwest::Client, segments: &[MediaSegment], keys: &HashMap<Url, [u8; 16]>,
+    segment_dir: &Path, throttle: &Throttle, progress: &DownloadProgress, disk_space_guard: &DiskSpaceGuard) -> GenericResult<u64>
+{
+    let mut download_futures = FuturesUnordered::new();
+    let mut finished_segments = 0;
+    let mut finished_bytes = 0;
+    let mut unchecked_bytes = 0;
+    let mut segment_finished = |segment_bytes: u64| {
+        finished_segments += 1;
+        finished_bytes += segment_bytes;
+        unchecked_bytes += segment_bytes;
+        let estimated_total_bytes = finished_bytes * segments.len() as u64 / finished_segments;
+        progress.set_total_bytes(Some(estimated_total_bytes));
+        if unchecked_bytes >= DISK_SPACE_CHECK_INTERVAL_BYTES {
+            unchecked_bytes = 0;
+            disk_space_guard.check(estimated_total_bytes - finished_bytes)?;
+        }
+        GenericResult::Ok(())
+    };
+
+    for (i, segment) in segments.iter().enumerate() {
+        let segment_path = segment_dir.join(i.to_string());
+        download_futures.push(async move {
+            let data = fetch_segment(client, segment, keys, throttle, progress).await?;
+            std::fs::write(segment_path, &data)?;
+            GenericResult::Ok(data.len() as u64)
+        });
 
+        if download_futures.len() >= MAX_PARALLEL_SEGMENT_DOWNLOADS {
+            segment_finished(download_futures.next().await.unwrap()?)?;
+        }
+    }
+    while let Some(result) = download_futures.next().await {
+        segment_finished(result?)?;
+    }
+    Ok(finished_bytes)
+}
+
+// Segments are removed once they are appended, s.t. the stream is only stored once
+fn concatenate_segments(segment_dir: &Path, segments_count: usize, out_path: &Path) -> GenericResult<()> {
+    let mut writer = BufWriter::new(File::create(out_path)?);
+    for i in 0..segments_count {
+        let segment_path = segment_dir.join(i.to_string());
+        writer.write_all(&std::fs::read(&segment_path)?)?;
+        std::fs::remove_file(segment_path)?;
+    }
+    writer.flush()?;
     Ok(())
 }
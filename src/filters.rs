@@ -0,0 +1,213 @@
+use regex::{Regex, RegexBuilder};
+use reqwest::Url;
+
+use crate::{GenericResult, data::{CourseFile, CourseFileMetadata, CourseFileResource, DownloadFilters, IgnoreRules},
+    naming::metadata_date_time};
+
+/// The `IgnoreRules` of a course, with their regular expressions compiled
+pub struct IgnoreMatcher {
+    url_regexes: Vec<Regex>,
+    title_regexes: Vec<Regex>,
+    section_names: Vec<String>
+}
+
+impl IgnoreMatcher {
+    pub fn new(rules: &IgnoreRules) -> GenericResult<Self> {
+        Ok(IgnoreMatcher {
+            url_regexes: compile_regexes(&rules.url_patterns)?,
+            title_regexes: compile_regexes(&rules.title_regexes)?,
+            section_names: rules.section_names.iter().map(|name| name.trim().to_lowercase()).collect()
+        })
+    }
+
+    pub fn is_ignored(&self, file: &CourseFile) -> bool {
+        let url = file.resource.url();
+        let title = file_title(&file.metadata);
+        self.url_regexes.iter().any(|regex| regex.is_match(url))
+            || self.title_regexes.iter().any(|regex| regex.is_match(title))
+            || file_section(&file.metadata).is_some_and(|section| self.section_names.contains(&section.trim().to_lowercase()))
+    }
+}
+
+/// The `DownloadFilters` of a course, with their regular expressions compiled
+pub struct DownloadFilter {
+    allowed_extensions: Vec<String>,
+    denied_extensions: Vec<String>,
+    max_size_bytes: Option<u64>,
+    section_regexes: Vec<Regex>,
+    title_regexes: Vec<Regex>,
+    discovered_after: Option<chrono::DateTime<chrono::Utc>>
+}
+
+impl DownloadFilter {
+    pub fn new(filters: &DownloadFilters) -> GenericResult<Self> {
+        let normalize_extensions = |extensions: &[String]| extensions.iter()
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .collect();
+        Ok(DownloadFilter {
+            allowed_extensions: normalize_extensions(&filters.allowed_extensions),
+            denied_extensions: normalize_extensions(&filters.denied_extensions),
+            max_size_bytes: filters.max_size_mib.map(|mib| mib * 1024 * 1024),
+            section_regexes: compile_regexes(&filters.section_regexes)?,
+            title_regexes: compile_regexes(&filters.title_regexes)?,
+            discovered_after: filters.discovered_after
+        })
+    }
+
+    /// Whether `file` passes all filters that can be checked without knowing its size. For `discovered_after`,
+    /// the file's own date is used if its metadata has one, otherwise `discovery_time`.
+    pub fn accepts(&self, file: &CourseFile, discovery_time: chrono::DateTime<chrono::Utc>) -> bool {
+        let extension = file_extension(&file.resource);
+        let extension_allowed = self.allowed_extensions.is_empty()
+            || extension.as_ref().is_some_and(|extension| self.allowed_extensions.contains(extension));
+        let extension_denied = extension.as_ref().is_some_and(|extension| self.denied_extensions.contains(extension));
+        let section = file_section(&file.metadata).unwrap_or_default();
+        let title = file_title(&file.metadata);
+        extension_allowed && !extension_denied
+            && (self.section_regexes.is_empty() || self.section_regexes.iter().any(|regex| regex.is_match(section)))
+            && (self.title_regexes.is_empty() || self.title_regexes.iter().any(|regex| regex.is_match(title)))
+            && self.discovered_after.is_none_or(|discovered_after|
+                metadata_date_time(&file.metadata).unwrap_or(discovery_time) > discovered_after)
+    }
+
+    /// Whether the size of a file has to be determined to decide if it passes the filters
+    pub fn needs_size(&self, file: &CourseFile) -> bool {
+        // The size of HLS streams is unknown before downloading them
+        self.max_size_bytes.is_some() && !matches!(file.resource, CourseFileResource::HlsStream { .. })
+    }
+
+    /// Whether a file of `size` bytes (`None` if unknown) passes the size filter
+    pub fn accepts_size(&self, size: Option<u64>) -> bool {
+        match (self.max_size_bytes, size) {
+            (Some(max_size_bytes), Some(size)) => size <= max_size_bytes,
+            _ => true
+        }
+    }
+}
+
+fn compile_regexes(patterns: &[String]) -> GenericResult<Vec<Regex>> {
+    patterns.iter()
+        .map(|pattern| Ok(RegexBuilder::new(pattern).case_insensitive(true).build()?))
+        .collect()
+}
+
+/// The size of a file, if it is already known from its metadata
+pub fn known_file_size(metadata: &CourseFileMetadata) -> Option<u64> {
+    match metadata {
+        CourseFileMetadata::MoodleActivity { file_size, .. } => *file_size,
+        _ => None
+    }
+}
+
+/// The (lowercase) extension of a file, from its metadata or url. Videos are downloaded as mp4 files.
+pub fn file_extension(resource: &CourseFileResource) -> Option<String> {
+    match resource {
+        CourseFileResource::Mp4File { .. } | CourseFileResource::HlsStream { .. } => Some("mp4".to_owned()),
+        CourseFileResource::Document { file_extension: Some(extension), .. } => Some(extension.to_lowercase()),
+        CourseFileResource::Document { url, file_extension: None } => {
+            let url = Url::parse(url).ok()?;
+            let filename = url.path_segments()?.next_back()?.to_owned();
+            let (_, extension) = filename.rsplit_once('.')?;
+            Some(extension.to_lowercase())
+        }
+    }
+}
+
+/// The title of a file as shown on the course website
+pub fn file_title(metadata: &CourseFileMetadata) -> &str {
+    match metadata {
+        CourseFileMetadata::TumLiveStream { video_title, .. } => video_title,
+        CourseFileMetadata::MoodleActivity { activity_title, .. } => activity_title,
+        CourseFileMetadata::WebsiteLink { link_text, .. } => link_text
+    }
+}
+
+/// The section of the course website a file was found in, if the website has sections
+pub fn file_section(metadata: &CourseFileMetadata) -> Option<&str> {
+    match metadata {
+        CourseFileMetadata::TumLiveStream { .. } | CourseFileMetadata::WebsiteLink { .. } => None,
+        CourseFileMetadata::MoodleActivity { section_title, .. } => Some(section_title)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moodle_document(url: &str, section_title: &str, activity_title: &str) -> CourseFile {
+        CourseFile {
+            resource: CourseFileResource::Document { url: url.to_owned(), file_extension: None },
+            metadata: CourseFileMetadata::MoodleActivity { lecture_title: "Analysis 1".to_owned(), section_title: section_title.to_owned(),
+                activity_title: activity_title.to_owned(), file_size: None, time_modified: None, folder_path: None }
+        }
+    }
+
+    #[test]
+    fn ignores_files_matching_any_rule() {
+        let rules = IgnoreRules {
+            url_patterns: vec![r"/old/".to_owned()],
+            title_regexes: vec!["^solution".to_owned()],
+            section_names: vec![" Archive ".to_owned()]
+        };
+        let matcher = IgnoreMatcher::new(&rules).unwrap();
+        assert!(matcher.is_ignored(&moodle_document("https://example.com/old/sheet.pdf", "Week 1", "Sheet 1")));
+        assert!(matcher.is_ignored(&moodle_document("https://example.com/sheet.pdf", "Week 1", "Solution 1")));
+        assert!(matcher.is_ignored(&moodle_document("https://example.com/sheet.pdf", "archive", "Sheet 1")));
+        assert!(!matcher.is_ignored(&moodle_document("https://example.com/sheet.pdf", "Week 1", "Sheet 1 with solutions")));
+    }
+
+    #[test]
+    fn rejects_invalid_ignore_rules() {
+        let rules = IgnoreRules { title_regexes: vec!["(unclosed".to_owned()], ..IgnoreRules::default() };
+        assert!(IgnoreMatcher::new(&rules).is_err());
+    }
+
+    #[test]
+    fn filters_by_extension_section_and_title() {
+        let filters = DownloadFilters {
+            allowed_extensions: vec![".PDF".to_owned(), "mp4".to_owned()],
+            denied_extensions: vec!["mp4".to_owned()],
+            section_regexes: vec!["^week".to_owned()],
+            title_regexes: vec!["sheet".to_owned()],
+            ..DownloadFilters::default()
+        };
+        let filter = DownloadFilter::new(&filters).unwrap();
+        let now = chrono::Utc::now();
+        assert!(filter.accepts(&moodle_document("https://example.com/sheet1.pdf", "Week 1", "Sheet 1"), now));
+        assert!(!filter.accepts(&moodle_document("https://example.com/sheet1.zip", "Week 1", "Sheet 1"), now));
+        assert!(!filter.accepts(&moodle_document("https://example.com/lecture.mp4", "Week 1", "Sheet 1"), now));
+        assert!(!filter.accepts(&moodle_document("https://example.com/sheet1.pdf", "Exam", "Sheet 1"), now));
+        assert!(!filter.accepts(&moodle_document("https://example.com/slides.pdf", "Week 1", "Slides"), now));
+    }
+
+    #[test]
+    fn filters_by_size_if_known() {
+        let filter = DownloadFilter::new(&DownloadFilters { max_size_mib: Some(1), ..DownloadFilters::default() }).unwrap();
+        assert!(filter.needs_size(&moodle_document("https://example.com/sheet1.pdf", "Week 1", "Sheet 1")));
+        assert!(filter.accepts_size(Some(1024 * 1024)));
+        assert!(!filter.accepts_size(Some(1024 * 1024 + 1)));
+        assert!(filter.accepts_size(None));
+    }
+
+    #[test]
+    fn discovered_after_compares_the_files_own_date() {
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(30);
+        let filter = DownloadFilter::new(&DownloadFilters { discovered_after: Some(cutoff), ..DownloadFilters::default() }).unwrap();
+        let now = chrono::Utc::now();
+        let mut file = moodle_document("https://example.com/sheet1.pdf", "Week 1", "Sheet 1");
+        // Without a date, the discovery time counts
+        assert!(filter.accepts(&file, now));
+        assert!(!filter.accepts(&file, cutoff - chrono::Duration::days(1)));
+        if let CourseFileMetadata::MoodleActivity { time_modified, .. } = &mut file.metadata {
+            *time_modified = Some(cutoff - chrono::Duration::days(100));
+        }
+        assert!(!filter.accepts(&file, now));
+
+        let stream = CourseFile {
+            resource: CourseFileResource::HlsStream { main_m3u8_url: "https://live.rbg.tum.de/w/ana/1".to_owned() },
+            metadata: CourseFileMetadata::TumLiveStream { lecture_title: "Analysis 1".to_owned(), video_title: "Lecture 1".to_owned(),
+                date_time_string: "2001-10-16 10:00".to_owned() }
+        };
+        assert!(!filter.accepts(&stream, now));
+    }
+}
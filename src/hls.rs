@@ -0,0 +1,276 @@
+use reqwest::Url;
+use simple_error::simple_error;
+use aes::cipher::{BlockDecryptMut, KeyIvInit, block_padding::Pkcs7};
+
+use crate::{GenericResult, data::{HlsVariant, HlsVariantPolicy}};
+
+type Aes128CbcDecryptor = cbc::Decryptor<aes::Aes128>;
+
+/// The AES-128 key that applies to a segment, as announced by `#EXT-X-KEY`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SegmentKey {
+    pub uri: Url,
+    pub iv: Option<[u8; 16]>
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaSegment {
+    pub url: Url,
+    pub sequence_number: u64,
+    pub key: Option<SegmentKey>
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaPlaylist {
+    pub segments: Vec<MediaSegment>,
+    /// Maximum segment duration in seconds (`#EXT-X-TARGETDURATION`)
+    pub target_duration: Option<u64>,
+    /// `false` for playlists of ongoing livestreams, i.e. if `#EXT-X-ENDLIST` is missing
+    pub ended: bool
+}
+
+impl MediaSegment {
+    /// The IV for decrypting this segment: the explicit IV of the key if present, otherwise the media sequence number
+    pub fn iv(&self) -> [u8; 16] {
+        self.key.as_ref().and_then(|key| key.iv)
+            .unwrap_or_else(|| (self.sequence_number as u128).to_be_bytes())
+    }
+}
+
+/// Returns `true` for lines of a playlist that are neither empty nor tags/comments, i.e. URIs
+pub fn is_uri_line(line: &&str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+pub fn is_master_playlist(playlist: &str) -> bool {
+    playlist.contains("#EXT-X-STREAM-INF")
+}
+
+/// Splits an HLS attribute list like `BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"` into key/value pairs.
+/// Quotes around values are removed.
+pub fn parse_attribute_list(attributes: &str) -> Vec<(String, String)> {
+    let mut pairs = vec![];
+    let mut rest = attributes.trim();
+    while !rest.is_empty() {
+        let (key, after_key) = match rest.find('=') {
+            Some(i) => (rest[..i].trim(), &rest[i+1..]),
+            None => break
+        };
+        let (value, after_value) = if let Some(quoted) = after_key.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            (&quoted[..end], quoted.get(end+1..).unwrap_or(""))
+        } else {
+            let end = after_key.find(',').unwrap_or(after_key.len());
+            (&after_key[..end], &after_key[end..])
+        };
+        pairs.push((key.to_owned(), value.trim().to_owned()));
+        rest = after_value.trim_start_matches(',').trim_start();
+    }
+    pairs
+}
+
+/// Parses all `#EXT-X-STREAM-INF` entries of a master playlist. Variant URIs are resolved against `playlist_url`.
+pub fn parse_master_playlist(playlist_url: &Url, playlist: &str) -> GenericResult<Vec<HlsVariant>> {
+    let mut variants = vec![];
+    let mut lines = playlist.lines();
+    while let Some(line) = lines.next() {
+        if let Some(attributes) = line.trim().strip_prefix("#EXT-X-STREAM-INF:") {
+            let uri = lines.by_ref().find(is_uri_line)
+                .ok_or(simple_error!("HLS master playlist {} has #EXT-X-STREAM-INF without URI", playlist_url))?;
+            let mut variant = HlsVariant {
+                url: playlist_url.join(uri.trim())?.to_string(),
+                bandwidth: 0,
+                resolution: None,
+                codecs: None
+            };
+            for (key, value) in parse_attribute_list(attributes) {
+                match key.as_str() {
+                    "BANDWIDTH" => { variant.bandwidth = value.parse().unwrap_or(0); },
+                    "RESOLUTION" => {
+                        variant.resolution = value.split_once('x')
+                            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+                    },
+                    "CODECS" => { variant.codecs = Some(value); },
+                    _ => {}
+                }
+            }
+            variants.push(variant);
+        }
+    }
+    Ok(variants)
+}
+
+/// Picks one of `variants` according to `policy`. Returns `None` only if `variants` is empty.
+pub fn select_variant<'a>(variants: &'a [HlsVariant], policy: &HlsVariantPolicy) -> Option<&'a HlsVariant> {
+    let highest = |candidates: &mut dyn Iterator<Item=&'a HlsVariant>| candidates
+        .max_by_key(|v| (v.bandwidth, v.resolution.map(|(_, height)| height)));
+    match policy {
+        HlsVariantPolicy::Highest => highest(&mut variants.iter()),
+        HlsVariantPolicy::Lowest => variants.iter()
+            .min_by_key(|v| (v.bandwidth, v.resolution.map(|(_, height)| height))),
+        HlsVariantPolicy::MaxResolution { height } => {
+            // Prefer the best variant that fits, otherwise fall back to the smallest one
+            highest(&mut variants.iter().filter(|v| matches!(v.resolution, Some((_, h)) if h <= *height)))
+                .or_else(|| select_variant(variants, &HlsVariantPolicy::Lowest))
+        },
+        HlsVariantPolicy::ClosestBandwidth { kbps } => variants.iter()
+            .min_by_key(|v| (v.bandwidth as i64 - *kbps as i64 * 1000).abs())
+    }
+}
+
+/// Parses the segments of a media playlist, including the encryption key that applies to each of them.
+/// Segment and key URIs are resolved against `playlist_url`.
+pub fn parse_media_playlist(playlist_url: &Url, playlist: &str) -> GenericResult<MediaPlaylist> {
+    let mut segments = vec![];
+    let mut sequence_number = 0;
+    let mut key = None;
+    let mut target_duration = None;
+    let mut ended = false;
+    for line in playlist.lines().map(str::trim) {
+        if line == "#EXT-X-ENDLIST" {
+            ended = true;
+        } else if let Some(duration) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            target_duration = duration.trim().parse().ok();
+        } else if let Some(first_sequence_number) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence_number = first_sequence_number.trim().parse()?;
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-KEY:") {
+            // A key applies to all following segments, until the next #EXT-X-KEY tag (key rotation)
+            key = parse_key(playlist_url, attributes)?;
+        } else if is_uri_line(&line) {
+            segments.push(MediaSegment { url: playlist_url.join(line)?, sequence_number, key: key.clone() });
+            sequence_number += 1;
+        }
+    }
+    Ok(MediaPlaylist { segments, target_duration, ended })
+}
+
+fn parse_key(playlist_url: &Url, attributes: &str) -> GenericResult<Option<SegmentKey>> {
+    let attributes = parse_attribute_list(attributes);
+    let attribute = |name: &str| attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    match attribute("METHOD") {
+        Some("NONE") => Ok(None),
+        Some("AES-128") => {
+            let uri = attribute("URI")
+                .ok_or(simple_error!("HLS playlist {} has AES-128 key without URI", playlist_url))?;
+            let iv = attribute("IV").map(parse_iv).transpose()?;
+            Ok(Some(SegmentKey { uri: playlist_url.join(uri)?, iv }))
+        },
+        method => Err(simple_error!("HLS encryption method {:?} is not supported", method).into())
+    }
+}
+
+// Parses a hexadecimal IV like `0x1234...` into its 16 bytes
+fn parse_iv(iv: &str) -> GenericResult<[u8; 16]> {
+    let hex = iv.trim_start_matches("0x").trim_start_matches("0X");
+    let iv = u128::from_str_radix(hex, 16)
+        .map_err(|_| simple_error!("Invalid HLS key IV '{}'", iv))?;
+    Ok(iv.to_be_bytes())
+}
+
+/// Decrypts an AES-128-CBC encrypted segment with PKCS7 padding
+pub fn decrypt_segment(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> GenericResult<Vec<u8>> {
+    Aes128CbcDecryptor::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| simple_error!("Could not decrypt HLS segment: invalid padding").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER_PLAYLIST: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=800000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+360p/playlist.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,RESOLUTION=1280x720
+720p/playlist.m3u8
+
+#EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080
+https://cdn.example.com/1080p/playlist.m3u8
+";
+
+    fn variants() -> Vec<HlsVariant> {
+        let playlist_url = Url::parse("https://example.com/stream/master.m3u8").unwrap();
+        parse_master_playlist(&playlist_url, MASTER_PLAYLIST).unwrap()
+    }
+
+    fn selected_height(policy: HlsVariantPolicy) -> Option<u32> {
+        select_variant(&variants(), &policy).and_then(|variant| variant.resolution).map(|(_, height)| height)
+    }
+
+    #[test]
+    fn parses_attribute_lists_with_quoted_commas() {
+        assert_eq!(parse_attribute_list("BANDWIDTH=1280000,CODECS=\"avc1.4d401f,mp4a.40.2\", RESOLUTION=1280x720"), vec![
+            ("BANDWIDTH".to_owned(), "1280000".to_owned()),
+            ("CODECS".to_owned(), "avc1.4d401f,mp4a.40.2".to_owned()),
+            ("RESOLUTION".to_owned(), "1280x720".to_owned())
+        ]);
+    }
+
+    #[test]
+    fn parses_master_playlist_variants() {
+        let variants = variants();
+        assert!(is_master_playlist(MASTER_PLAYLIST));
+        assert_eq!(variants.len(), 3);
+        assert_eq!(variants[0].url, "https://example.com/stream/360p/playlist.m3u8");
+        assert_eq!(variants[0].bandwidth, 800000);
+        assert_eq!(variants[0].resolution, Some((640, 360)));
+        assert_eq!(variants[0].codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+        assert_eq!(variants[1].codecs, None);
+        assert_eq!(variants[2].url, "https://cdn.example.com/1080p/playlist.m3u8");
+    }
+
+    #[test]
+    fn selects_variants_by_policy() {
+        assert_eq!(selected_height(HlsVariantPolicy::Highest), Some(1080));
+        assert_eq!(selected_height(HlsVariantPolicy::Lowest), Some(360));
+        assert_eq!(selected_height(HlsVariantPolicy::MaxResolution { height: 720 }), Some(720));
+        assert_eq!(selected_height(HlsVariantPolicy::ClosestBandwidth { kbps: 3000 }), Some(720));
+    }
+
+    #[test]
+    fn falls_back_to_lowest_variant_if_none_fits_max_resolution() {
+        assert_eq!(selected_height(HlsVariantPolicy::MaxResolution { height: 240 }), Some(360));
+        assert!(select_variant(&[], &HlsVariantPolicy::Highest).is_none());
+    }
+
+    #[test]
+    fn parses_media_playlist_segments_and_keys() {
+        let playlist_url = Url::parse("https://example.com/stream/720p/playlist.m3u8").unwrap();
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:41
+#EXTINF:6.0,
+segment41.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:6.0,
+segment42.ts
+#EXT-X-ENDLIST
+";
+        let media_playlist = parse_media_playlist(&playlist_url, playlist).unwrap();
+        assert_eq!(media_playlist.target_duration, Some(6));
+        assert!(media_playlist.ended);
+        assert_eq!(media_playlist.segments.len(), 2);
+
+        let first_segment = &media_playlist.segments[0];
+        assert_eq!(first_segment.url.as_str(), "https://example.com/stream/720p/segment41.ts");
+        assert_eq!(first_segment.sequence_number, 41);
+        assert_eq!(first_segment.key, None);
+
+        let second_segment = &media_playlist.segments[1];
+        assert_eq!(second_segment.sequence_number, 42);
+        let key = second_segment.key.as_ref().unwrap();
+        assert_eq!(key.uri.as_str(), "https://example.com/stream/720p/key.bin");
+        assert_eq!(second_segment.iv(), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn livestream_playlists_are_not_ended() {
+        let playlist_url = Url::parse("https://example.com/live/playlist.m3u8").unwrap();
+        let media_playlist = parse_media_playlist(&playlist_url, "#EXTM3U\n#EXTINF:2.0,\nsegment0.ts\n").unwrap();
+        assert!(!media_playlist.ended);
+        assert_eq!(media_playlist.target_duration, None);
+        // Without explicit IV, the sequence number is used
+        assert_eq!(media_playlist.segments[0].iv(), [0; 16]);
+    }
+}
@@ -2,11 +2,21 @@ use std::error::Error;
 
 pub mod data;
 pub mod moodle;
-#[allow(dead_code, unused_variables)] // As of now, `download` contains unused legacy functions possibly needed in the future for proper TUM Live integration
 pub mod download;
+pub mod hls;
+pub mod scheduler;
+pub mod bandwidth;
+pub mod naming;
 pub mod tum_live;
 pub mod postprocessing;
 pub mod http_headers;
+pub mod progress;
+pub mod disk_space;
+pub mod retention;
+pub mod filters;
+pub mod recovery;
+pub mod website;
+pub mod moodle_api;
 
 /* TODOs
 - parse live.rgb.tum lecture page, extract m3u8 urls
@@ -2,7 +2,7 @@ use reqwest::{self, Url};
 use std::{fmt::Display, pin::Pin, sync::Arc};
 use regex::Regex;
 use futures::{self, TryFutureExt, stream::{StreamExt, FuturesOrdered}, Future, future::BoxFuture};
-use select::{document::Document,
+use select::{document::Document, node::Node,
             predicate::{Predicate, Attr, Class, Name, Text}};
 use simple_error::simple_error;
 use reqwest_cookie_store::CookieStoreMutex;
@@ -32,12 +32,13 @@ lazy_static! {
 }
 
 const PANOPTO_LOGIN_URL: &str = "https://tum.cloud.panopto.eu/Panopto/Pages/Auth/Login.aspx?Auth=Viewer&instance=moodle&AllowBounce=true";
-const MOODLE_URL: &str = "https://www.moodle.tum.de/";
+pub(crate) const MOODLE_URL: &str = "https://www.moodle.tum.de/";
 const MOODLE_LOGIN_LINK_TEXT: &str = "TUM-Kennung";
 
-enum CourseFileOrSubpage { CourseFile(CourseFile), Subpage { subpage_depth: i32, subpage_url: String } }
+enum CourseFileOrSubpage { CourseFile(CourseFile), Subpage { subpage_depth: i32, subpage_url: String }, FolderFiles(Vec<CourseFile>) }
 
 type CourseFileFuture = Pin<Box<dyn Send + Future<Output=GenericResult<Option<CourseFileOrSubpage>>>>>;
+type SubpageFuture<'a> = BoxFuture<'a, GenericResult<FuturesOrdered<BoxFuture<'a, GenericResult<Option<CourseFileOrSubpage>>>>>>;
 
 async fn detect_moodle_files_and_subpages<'a>(site_url: String, client: &reqwest::Client,
     crawled_urls: &flurry::HashSet<String>, // mutable threadsafe hashset (despite not declared &mut)
@@ -46,37 +47,68 @@ async fn detect_moodle_files_and_subpages<'a>(site_url: String, client: &reqwest
     let mut detect_futures: FuturesOrdered<BoxFuture<GenericResult<Option<CourseFileOrSubpage>>>> = FuturesOrdered::new();
 
     let resp = client.get(&site_url).timeout(*DEFAULT_TIMEOUT).send().await?;
+    let page_url = resp.url().clone();
     { // Artificial scope s.t. the non-`Send` `course_page_dom` is dropped before the next .await
         let course_page_dom = Document::from(resp.text().await?.as_str());
-        let lecture_title = course_page_dom.find(Class("page-header-headings")).next().map(|n| n.text()).unwrap_or_default();
+        let lecture_title = course_page_dom.find(Class("page-header-headings")).next()
+            .or_else(|| course_page_dom.find(Attr("id", "page-header").descendant(Name("h1"))).next())
+            .map(|n| n.text()).unwrap_or_default();
 
         let crawled_urls_guard = crawled_urls.guard(); // Required for `flurry` HashSet implementation
 
-        // Iterate through main sections to capture their titles
-        for section_node in course_page_dom.find(Class("section").and(Class("main"))) {
-            let section_title = section_node.find(Class("sectionname")).next().map(|n| n.text()).unwrap_or_default();
-           
-            // Iterate through nodes that could be directly linked videos/documents
-            for activity_node in section_node.find(Class("activityinstance"))
+        // Iterate through nodes that could be directly linked videos/documents, and capture the titles of their sections
+        for activity_node in course_page_dom.find(is_activity_node)
+        {
+            if let Some(activity_url) = activity_link(activity_node).and_then(|n| n.attr("href"))
             {
-                if let Some(activity_url) = activity_node.find(Name("a")).next()
-                    .and_then(|n| n.attr("href"))
-                {
-                    if crawled_urls.insert(activity_url.to_owned(), &crawled_urls_guard) {
-                        let activity_title = activity_node.find(Class("instancename")).next().map(|n| n.text()).unwrap_or_default();
-                        let detect_future = detect_moodle_course_file(&client, activity_url, lecture_title.clone(), section_title.clone(), activity_title, depth);
-                        detect_futures.push(detect_future);
-                    }
+                if crawled_urls.insert(activity_url.to_owned(), &crawled_urls_guard) {
+                    let section_title = enclosing_section(activity_node).map(section_title).unwrap_or_default();
+                    let activity_title = activity_node.find(Class("instancename")).next().map(|n| n.text()).unwrap_or_default();
+                    let detect_future = if is_folder_activity_url(activity_url) {
+                        detect_moodle_folder_files(client, activity_url, lecture_title.clone(), section_title, activity_title)
+                    } else {
+                        detect_moodle_course_file(client, activity_url, lecture_title.clone(), section_title, activity_title, depth)
+                    };
+                    detect_futures.push(detect_future);
                 }
             }
+        }
 
-            // Iterate through nodes that could be embedded Panopto players
-            for video_node in section_node.find(Name("iframe").and(Attr("src", ())))
-            {
-                // Video in embedded Panopto player
-                if video_node.name() == Some("iframe") && video_node.attr("src").unwrap().contains("panopto") {
-                    let detect_video_future = detect_panopto_video_file(video_node, &lecture_title, &section_title, client, depth);
-                    detect_futures.push(Box::pin(detect_video_future));
+        // Iterate through nodes that could be embedded Panopto players
+        for video_node in course_page_dom.find(Name("iframe").and(Attr("src", ())))
+        {
+            // Video in embedded Panopto player
+            if let Some(section_node) = enclosing_section(video_node).filter(|_| video_node.attr("src").unwrap().contains("panopto")) {
+                let detect_video_future = detect_panopto_video_file(video_node, &lecture_title, &section_title(section_node), client, depth);
+                detect_futures.push(Box::pin(detect_video_future));
+            }
+        }
+
+        // Course formats like tiles and onetopic only show one section at a time, with links to the other sections.
+        // Since these are part of the course page, they are crawled at the same depth.
+        for link_node in course_page_dom.find(Name("a").and(Attr("href", ()))) {
+            let section_url = page_url.join(link_node.attr("href").unwrap()).ok()
+                .filter(|url| is_course_section_link(&page_url, url));
+            if let Some(mut section_url) = section_url {
+                section_url.set_fragment(None);
+                if crawled_urls.insert(section_url.to_string(), &crawled_urls_guard) {
+                    let subpage = CourseFileOrSubpage::Subpage { subpage_url: section_url.to_string(), subpage_depth: depth };
+                    detect_futures.push(Box::pin(async move { Ok(Some(subpage)) }));
+                }
+            }
+        }
+
+        // Folders can also be displayed inline on the course page
+        for link_node in course_page_dom.find(Name("a").and(Attr("href", ()))) {
+            let link_url = link_node.attr("href").unwrap();
+            if is_folder_file_url(link_url) && crawled_urls.insert(link_url.to_owned(), &crawled_urls_guard) {
+                let section_title = enclosing_section(link_node).map(section_title).unwrap_or_default();
+                let activity_title = std::iter::successors(link_node.parent(), Node::parent)
+                    .find(|n| n.is(Class("activity")))
+                    .and_then(|n| n.find(Class("instancename").or(Class("activityname"))).next())
+                    .map(|n| n.text()).unwrap_or_default();
+                if let Some(course_file) = moodle_folder_file(link_url, lecture_title.clone(), section_title, activity_title) {
+                    detect_futures.push(Box::pin(async move { Ok(Some(CourseFileOrSubpage::CourseFile(course_file))) }));
                 }
             }
         }
@@ -88,7 +120,7 @@ async fn detect_moodle_files_and_subpages<'a>(site_url: String, client: &reqwest
                 if let Some(link_url) = link_node.attr("href") {
                     // Insert the link url and only continue if not yet present
                     if crawled_urls.insert(link_url.to_owned(), &crawled_urls_guard) {
-                        let detect_future = detect_moodle_course_file(&client, link_url, lecture_title.clone(), String::new(), link_node.text(), depth);
+                        let detect_future = detect_moodle_course_file(client, link_url, lecture_title.clone(), String::new(), link_node.text(), depth);
                         detect_futures.push(detect_future);
                     }
                 }
@@ -98,7 +130,50 @@ async fn detect_moodle_files_and_subpages<'a>(site_url: String, client: &reqwest
     Ok(detect_futures)
 }
 
-fn detect_panopto_video_file(video_node: select::node::Node, lecture_title: &str, section_title: &str, client: &reqwest::Client, depth: i32)
+// Moodle 3.x marks sections with `section main`, Moodle 4.x with `course-section` and `data-for="section"`
+fn is_section_node(node: &Node) -> bool {
+    (node.is(Class("section")) && node.is(Class("main")))
+        || node.is(Class("course-section"))
+        || node.attr("data-for") == Some("section")
+}
+
+// Moodle 3.x wraps activity links in `activityinstance`, Moodle 4.x uses `activity-item`
+fn is_activity_node(node: &Node) -> bool {
+    node.is(Class("activityinstance")) || node.is(Class("activity-item"))
+}
+
+// Moodle 4.x activity items also contain descriptions and completion buttons, the activity link is inside `activityname`
+fn activity_link(activity_node: Node) -> Option<Node> {
+    let link_container = if activity_node.is(Class("activity-item")) {
+        activity_node.find(Class("activityname")).next()?
+    } else { activity_node };
+    link_container.find(Name("a")).next()
+}
+
+// Sections may be nested (e.g. Moodle 4.5 subsections), so the innermost section is used
+fn enclosing_section(node: Node) -> Option<Node> {
+    std::iter::successors(node.parent(), Node::parent).find(is_section_node)
+}
+
+fn section_title(section_node: Node) -> String {
+    section_node.find(Attr("data-for", "section_title")).next()
+        .or_else(|| section_node.find(Class("sectionname")).next())
+        .map(|n| n.text())
+        .filter(|title| !title.trim().is_empty())
+        .or_else(|| section_node.attr("aria-label").map(str::to_owned))
+        .unwrap_or_default()
+}
+
+// Links to single sections of the course shown on `page_url`, like `course/view.php?id=1&section=2`
+fn is_course_section_link(page_url: &Url, url: &Url) -> bool {
+    let course_id = |url: &Url| url.query_pairs().find(|(name, _)| name == "id").map(|(_, id)| id.into_owned());
+    let is_course_page = |url: &Url| url.path().ends_with("/course/view.php");
+    is_course_page(page_url) && is_course_page(url)
+        && url.query_pairs().any(|(name, _)| name == "section")
+        && course_id(url).is_some() && course_id(url) == course_id(page_url)
+}
+
+fn detect_panopto_video_file(video_node: Node, lecture_title: &str, section_title: &str, client: &reqwest::Client, depth: i32)
         -> CourseFileFuture
 {
     // Recieve embedded player HTML and extract video url and title from it
@@ -124,7 +199,7 @@ fn detect_panopto_video_file(video_node: select::node::Node, lecture_title: &str
                     moodle_course_file(video_url, lecture_title, section_title, video_title, depth)
                 })
         });
-    return Box::pin(detect_video_future)
+    Box::pin(detect_video_future)
 }
 
 pub async fn detect_moodle_files(course_url: &str, moodle_auth_cookies: Arc<CookieStoreMutex>, max_depth: i32)
@@ -137,13 +212,14 @@ pub async fn detect_moodle_files(course_url: &str, moodle_auth_cookies: Arc<Cook
 
     // Set up future collections and a threadsafe HashSet to track all crawled urls
     let crawled_urls = flurry::HashSet::new();
-    let mut subpage_futures: FuturesOrdered<BoxFuture<GenericResult<FuturesOrdered<BoxFuture<GenericResult<Option<CourseFileOrSubpage>>>>>>> = FuturesOrdered::new();
+    let mut subpage_futures: FuturesOrdered<SubpageFuture> = FuturesOrdered::new();
     let mut detect_futures = detect_moodle_files_and_subpages(course_url.to_owned(), &client, &crawled_urls, 0).await?;
 
     let mut course_files = vec![];
     let mut errors: Vec<GenericError> = vec![];
 
-    while detect_futures.len() > 0 {
+    // A subpage may not yield any new futures, so also continue while other subpages are pending
+    while !detect_futures.is_empty() || !subpage_futures.is_empty() {
         // Complete all currently available file detection futures
         while let Some(result) = detect_futures.next().await {
             match result {
@@ -156,8 +232,13 @@ pub async fn detect_moodle_files(course_url: &str, moodle_auth_cookies: Arc<Cook
                         subpage_futures.push(Box::pin(subpage_future));
                     }
                 },
+                Ok(Some(CourseFileOrSubpage::FolderFiles(folder_files))) => {
+                    let crawled_urls_guard = crawled_urls.guard();
+                    course_files.extend(folder_files.into_iter()
+                        .filter(|course_file| crawled_urls.insert(course_file.resource.url().to_owned(), &crawled_urls_guard)));
+                },
                 Ok(None) => {} // Ignore (e.g. unwanted file types)
-                Err(error) => { errors.push(error.into()); } // Like this for now, but just skipping would also be an option
+                Err(error) => { errors.push(error); } // Like this for now, but just skipping would also be an option
             }
         }
         // Complete the next subpage detection future and collect nested file detection futures to be executed next
@@ -166,12 +247,12 @@ pub async fn detect_moodle_files(course_url: &str, moodle_auth_cookies: Arc<Cook
                 Ok(subpage_file_detect_futures) => {
                     detect_futures = subpage_file_detect_futures;
                 },
-                Err(error) => { errors.push(error.into()); } // Like this for now, but just skipping would also be an option
+                Err(error) => { errors.push(error); } // Like this for now, but just skipping would also be an option
             }
         }
     }
 
-    if errors.len() == 0 {
+    if errors.is_empty() {
         Ok(course_files)
     } else {
         Err(MoodleCrawlingError{successful_detections: course_files, failed_detections: errors}.into())
@@ -183,7 +264,7 @@ fn detect_moodle_course_file(client: &reqwest::Client, url: &str, lecture_title:
     activity_title: String, depth: i32)
     -> CourseFileFuture
 {
-    if Url::parse(&url).is_err() {
+    if Url::parse(url).is_err() {
         // Don't try to follow an URL that can't even be parsed.
         return Box::pin(async {GenericResult::Ok(None)});
     }
@@ -195,37 +276,100 @@ fn detect_moodle_course_file(client: &reqwest::Client, url: &str, lecture_title:
             let url = resp.url().to_string();
             moodle_course_file(url, lecture_title, section_title, activity_title, depth)
         });
-    return Box::pin(detect_file_future);
+    Box::pin(detect_file_future)
 }
 
 fn moodle_course_file(url: String, lecture_title: String, section_title: String, activity_title: String, depth: i32) -> Option<CourseFileOrSubpage> {
-    let metadata = CourseFileMetadata::MoodleActivity { lecture_title, section_title, activity_title };
-    let url_parsed = Url::parse(&url).ok()?;
-    let file_extension = url_parsed.path_segments()
-        .and_then(|p| p.into_iter().last())
-        .map(|s| s.rfind(".")
-        .map(|i| (&s[i+1..]).to_lowercase()));
-
-    let (resource, subpage) = match file_extension {
+    let metadata = CourseFileMetadata::MoodleActivity { lecture_title, section_title, activity_title, file_size: None,
+        time_modified: None, folder_path: None };
+    match classify_link(url) {
+        LinkTarget::Resource(resource) => Some(CourseFileOrSubpage::CourseFile(CourseFile { metadata, resource })),
+        LinkTarget::Subpage(subpage_url) => Some(CourseFileOrSubpage::Subpage { subpage_url, subpage_depth: depth + 1 }),
+        LinkTarget::WithoutExtension(_) | LinkTarget::Ignored => None
+    }
+}
+
+fn is_folder_activity_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.path().ends_with("/mod/folder/view.php"))
+}
+
+fn is_folder_file_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| url.path().contains("/mod_folder/content/"))
+}
+
+// Instead of crawling a folder activity's page as subpage, its whole file tree is detected at once,
+// so that the files keep the section and activity title of the folder
+fn detect_moodle_folder_files(client: &reqwest::Client, url: &str, lecture_title: String, section_title: String,
+    activity_title: String) -> CourseFileFuture
+{
+    let detect_files_future = client.get(url).timeout(*DEFAULT_TIMEOUT).send().err_into::<GenericError>()
+        .and_then(|resp| resp.text().err_into::<GenericError>())
+        .map_ok(move |text| {
+            let folder_page_dom = Document::from(text.as_str());
+            let folder_files = folder_page_dom.find(Name("a"))
+                .filter_map(|n| n.attr("href"))
+                .filter(|url| is_folder_file_url(url))
+                .filter_map(|url| moodle_folder_file(url, lecture_title.clone(), section_title.clone(), activity_title.clone()))
+                .collect();
+            Some(CourseFileOrSubpage::FolderFiles(folder_files))
+        });
+    Box::pin(detect_files_future)
+}
+
+// Folder files have urls like `.../pluginfile.php/<context>/mod_folder/content/<revision>/<directories...>/<filename>`,
+// so the directory inside the folder is taken from the url
+fn moodle_folder_file(url: &str, lecture_title: String, section_title: String, activity_title: String) -> Option<CourseFile> {
+    let url_parsed = Url::parse(url).ok()?;
+    let segments = url_parsed.path_segments()?.collect::<Vec<_>>();
+    let content_index = segments.windows(2).position(|window| window == ["mod_folder", "content"])? + 1;
+    let directories = segments.get(content_index + 2..segments.len() - 1)?;
+    let folder_path = directories.iter()
+        .map(|directory| urlencoding::decode(directory).map_or_else(|_| directory.to_string(), |directory| directory.into_owned()))
+        .collect::<Vec<_>>()
+        .join("/");
+    let metadata = CourseFileMetadata::MoodleActivity { lecture_title, section_title, activity_title, file_size: None,
+        time_modified: None, folder_path: Some(folder_path) };
+    match classify_link(url.to_owned()) {
+        LinkTarget::Resource(resource) => Some(CourseFile { metadata, resource }),
+        _ => None
+    }
+}
+
+/// What a link on a course page points to, judging by the file extension of its url
+pub enum LinkTarget {
+    Resource(CourseFileResource),
+    /// HTML and PHP files are considered to be subpages
+    Subpage(String),
+    /// The url does not end with a file extension
+    WithoutExtension(String),
+    Ignored
+}
+
+pub fn classify_link(url: String) -> LinkTarget {
+    let file_extension = match Url::parse(&url) {
+        Ok(url_parsed) => url_parsed.path_segments()
+            .and_then(|mut p| p.next_back())
+            .map(|s| s.rfind('.')
+            .map(|i| s[i+1..].to_lowercase())),
+        // URL parsing failed
+        Err(_) => return LinkTarget::Ignored
+    };
+
+    match file_extension {
         Some(Some(extension)) => {
             match extension.as_str() {
-                "mp4" => (Some(CourseFileResource::Mp4File {url}), None),
-                "m3u8" => (Some(CourseFileResource::HlsStream {main_m3u8_url: url}), None),
-                // HTML and PHP files are considered to link to subpages
-                "html" | "php" => (None, Some(CourseFileOrSubpage::Subpage { subpage_url: url, subpage_depth: depth + 1})),
-                "aspx" => (None, None), // Ignore aspx files (aspx links appear for Panopto videos, but are not useful to us)
-                _ => (Some(CourseFileResource::Document {url, file_extension: Some(extension)}), None)
+                "mp4" => LinkTarget::Resource(CourseFileResource::Mp4File {url}),
+                "m3u8" => LinkTarget::Resource(CourseFileResource::HlsStream {main_m3u8_url: url}),
+                "html" | "php" => LinkTarget::Subpage(url),
+                "aspx" => LinkTarget::Ignored, // Ignore aspx files (aspx links appear for Panopto videos, but are not useful to us)
+                _ => LinkTarget::Resource(CourseFileResource::Document {url, file_extension: Some(extension)})
             }
         }
-        // Some(None) means: URL parsing worked, but there is not . indicating a file extension. For now: ignore such files.
-        Some(None) => (None, None), // alternatively: Some(CourseFileResource::Document {url, file_extension: None}),
-        // None means: URL parsing failed
-        None => (None, None)
-    };
-    if let Some(resource) = resource {
-        return Some(CourseFileOrSubpage::CourseFile(CourseFile { metadata, resource }));
+        // Some(None) means: URL parsing worked, but there is not . indicating a file extension.
+        // Moodle ignores such files, alternatively: Some(CourseFileResource::Document {url, file_extension: None}),
+        Some(None) => LinkTarget::WithoutExtension(url),
+        None => LinkTarget::Ignored
     }
-    return subpage;
 }
 
 fn extract_html_input_value<'a>(document: &'a Document, input_name: &str) -> GenericResult<&'a str> {
@@ -294,5 +438,44 @@ pub async fn moodle_login(username: &str, password: &str) -> GenericResult<Arc<C
         return Err(simple_error!("Panopto login did not succeed!").into())
     }
 
-    return Ok(cookie_store)
+    Ok(cookie_store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn recognizes_section_links_of_the_same_course() {
+        let page_url = url("https://www.moodle.tum.de/course/view.php?id=42");
+        assert!(is_course_section_link(&page_url, &url("https://www.moodle.tum.de/course/view.php?id=42&section=3")));
+        assert!(is_course_section_link(&page_url, &url("https://www.moodle.tum.de/course/view.php?section=1&id=42#section-1")));
+        assert!(!is_course_section_link(&page_url, &url("https://www.moodle.tum.de/course/view.php?id=43&section=3")));
+        assert!(!is_course_section_link(&page_url, &url("https://www.moodle.tum.de/course/view.php?id=42")));
+        assert!(!is_course_section_link(&page_url, &url("https://www.moodle.tum.de/mod/page/view.php?id=42&section=3")));
+        let section_page_url = url("https://www.moodle.tum.de/course/view.php?id=42&section=2");
+        assert!(is_course_section_link(&section_page_url, &url("https://www.moodle.tum.de/course/view.php?id=42&section=3")));
+        assert!(!is_course_section_link(&url("https://www.moodle.tum.de/mod/folder/view.php?id=42"),
+            &url("https://www.moodle.tum.de/course/view.php?id=42&section=3")));
+    }
+
+    #[test]
+    fn detects_files_in_moodle_folders() {
+        let folder_file = |url: &str| moodle_folder_file(url, "Analysis 1".to_owned(), "Week 1".to_owned(), "Exercises".to_owned());
+        let file = folder_file("https://www.moodle.tum.de/pluginfile.php/123/mod_folder/content/4/Sheet%201/solutions/sol.pdf?forcedownload=1")
+            .unwrap();
+        assert!(matches!(file.resource, CourseFileResource::Document { ref file_extension, .. } if file_extension.as_deref() == Some("pdf")));
+        assert!(matches!(file.metadata, CourseFileMetadata::MoodleActivity { ref activity_title, ref folder_path, .. }
+            if activity_title == "Exercises" && folder_path.as_deref() == Some("Sheet 1/solutions")));
+
+        let file = folder_file("https://www.moodle.tum.de/pluginfile.php/123/mod_folder/content/4/sheet.pdf").unwrap();
+        assert!(matches!(file.metadata, CourseFileMetadata::MoodleActivity { ref folder_path, .. } if folder_path.as_deref() == Some("")));
+
+        assert!(folder_file("https://www.moodle.tum.de/pluginfile.php/123/mod_resource/content/4/sheet.pdf").is_none());
+        assert!(folder_file("https://www.moodle.tum.de/pluginfile.php/123/mod_folder/content/4/index.html").is_none());
+    }
 }
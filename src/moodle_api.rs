@@ -0,0 +1,188 @@
+use reqwest::{self, Url, header, redirect};
+use std::sync::Arc;
+use chrono::TimeZone;
+use serde::{Deserialize, de::DeserializeOwned};
+use simple_error::simple_error;
+use reqwest_cookie_store::CookieStoreMutex;
+
+use crate::{GenericResult, data::{CourseFile, CourseFileMetadata}, http_headers::DEFAULT_HEADERS,
+    moodle::{LinkTarget, MOODLE_URL, classify_link}};
+
+/// The web service that the Moodle mobile app uses, which is enabled on most Moodle sites
+const MOODLE_MOBILE_SERVICE: &str = "moodle_mobile_app";
+const MOODLE_MOBILE_URL_SCHEME: &str = "moodlemobile";
+const MAX_LAUNCH_REDIRECTS: usize = 10;
+
+#[derive(Deserialize)]
+struct SiteInfo {
+    userid: u64
+}
+
+#[derive(Deserialize)]
+struct EnrolledCourse {
+    id: u64,
+    fullname: String
+}
+
+#[derive(Deserialize)]
+struct CourseSection {
+    name: String,
+    #[serde(default)]
+    modules: Vec<CourseModule>
+}
+
+#[derive(Deserialize)]
+struct CourseModule {
+    name: String,
+    modname: String,
+    #[serde(default)]
+    contents: Vec<ModuleContent>
+}
+
+#[derive(Deserialize)]
+struct ModuleContent {
+    #[serde(rename = "type")]
+    content_type: String,
+    #[serde(default)]
+    filepath: Option<String>,
+    #[serde(default)]
+    fileurl: Option<String>,
+    #[serde(default)]
+    filesize: Option<u64>,
+    #[serde(default)]
+    timemodified: Option<i64>
+}
+
+/// Obtains a web service token for the Moodle account logged in with `moodle_auth_cookies` (see `moodle_login`).
+/// Since TUM Moodle only supports Shibboleth logins, the token is requested through the SSO launch flow of the
+/// Moodle mobile app, which hands out the token by redirecting to a `moodlemobile://` url.
+pub async fn moodle_web_service_token(moodle_auth_cookies: Arc<CookieStoreMutex>) -> GenericResult<String> {
+    // Redirects are followed manually, since the final redirect goes to the mobile app's url scheme
+    let client = reqwest::Client::builder()
+        .cookie_provider(moodle_auth_cookies)
+        .default_headers((*DEFAULT_HEADERS).clone())
+        .redirect(redirect::Policy::none())
+        .build()?;
+
+    let mut url = Url::parse(MOODLE_URL)?.join("admin/tool/mobile/launch.php")?;
+    url.query_pairs_mut()
+        .append_pair("service", MOODLE_MOBILE_SERVICE)
+        .append_pair("passport", &chrono::Utc::now().timestamp_millis().to_string())
+        .append_pair("urlscheme", MOODLE_MOBILE_URL_SCHEME);
+
+    for _ in 0..MAX_LAUNCH_REDIRECTS {
+        let resp = client.get(url.clone()).send().await?.error_for_status()?;
+        let location = resp.headers().get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| simple_error!("Moodle did not hand out a web service token, is the login still valid?"))?;
+        match location.strip_prefix(&format!("{}://token=", MOODLE_MOBILE_URL_SCHEME)) {
+            Some(encoded_token) => return decode_launch_token(encoded_token),
+            None => url = url.join(location)?
+        }
+    }
+    Err(simple_error!("Too many redirects while obtaining a Moodle web service token").into())
+}
+
+// The launch token has the format `base64(<signature>:::<token>[:::<private token>])`
+fn decode_launch_token(encoded_token: &str) -> GenericResult<String> {
+    let decoded_token = String::from_utf8(base64::decode(encoded_token.trim())?)?;
+    decoded_token.split(":::").nth(1)
+        .filter(|token| !token.is_empty())
+        .map(str::to_owned)
+        .ok_or_else(|| simple_error!("Unexpected Moodle launch token format").into())
+}
+
+// Calls a function of Moodle's REST web service
+async fn call_web_service<T: DeserializeOwned>(client: &reqwest::Client, site_url: &Url, token: &str, function: &str,
+    params: &[(&str, String)]) -> GenericResult<T>
+{
+    let mut form = vec![
+        ("wstoken", token.to_owned()),
+        ("wsfunction", function.to_owned()),
+        ("moodlewsrestformat", "json".to_owned())
+    ];
+    form.extend(params.iter().map(|(name, value)| (*name, value.clone())));
+    let response_text = client.post(site_url.join("webservice/rest/server.php")?)
+        .form(&form)
+        .send().await?
+        .error_for_status()?
+        .text().await?;
+    let response: serde_json::Value = serde_json::from_str(&response_text)?;
+    // Errors are reported with a success status code, as an object with `exception`, `errorcode` and `message`
+    if response.get("exception").is_some() || response.get("errorcode").is_some() {
+        let message = response.get("message").and_then(|message| message.as_str()).unwrap_or("unknown error");
+        return Err(simple_error!("Moodle web service function {} failed: {}", function, message).into());
+    }
+    Ok(serde_json::from_value(response)?)
+}
+
+// The Moodle site a course belongs to, e.g. `https://www.moodle.tum.de/` for `https://www.moodle.tum.de/course/view.php?id=1`
+fn moodle_site_url(course_url: &Url) -> GenericResult<Url> {
+    let path = course_url.path();
+    let site_path = path.find("/course/").map(|index| &path[..=index])
+        .ok_or_else(|| simple_error!("{} is not a Moodle course url", course_url))?;
+    Ok(course_url.join(site_path)?)
+}
+
+// Files listed by the web service can only be downloaded with the token. The same files are available with the
+// session cookies from the regular `pluginfile.php` url, which also keeps the token out of the stored state.
+fn session_file_url(mut file_url: Url) -> Url {
+    let path = file_url.path().replacen("/webservice/pluginfile.php/", "/pluginfile.php/", 1);
+    file_url.set_path(&path);
+    file_url.set_query(None);
+    file_url
+}
+
+/// Detects the files of a Moodle course through Moodle's web service API instead of crawling the course page.
+/// Sections and activities are taken from `core_course_get_contents`, the course title from `core_enrol_get_users_courses`.
+pub async fn detect_moodle_files_via_api(course_url: &str, token: &str) -> GenericResult<Vec<CourseFile>> {
+    let course_url = Url::parse(course_url)?;
+    let site_url = moodle_site_url(&course_url)?;
+    let course_id = course_url.query_pairs()
+        .find(|(name, _)| name == "id")
+        .ok_or_else(|| simple_error!("Moodle course url {} has no course id", course_url))?
+        .1.parse::<u64>()?;
+
+    let client = reqwest::Client::builder()
+        .default_headers((*DEFAULT_HEADERS).clone())
+        .build()?;
+
+    let site_info: SiteInfo = call_web_service(&client, &site_url, token, "core_webservice_get_site_info", &[]).await?;
+    let enrolled_courses: Vec<EnrolledCourse> = call_web_service(&client, &site_url, token,
+        "core_enrol_get_users_courses", &[("userid", site_info.userid.to_string())]).await?;
+    let lecture_title = enrolled_courses.into_iter()
+        .find(|course| course.id == course_id)
+        .ok_or_else(|| simple_error!("Not enrolled in Moodle course {}", course_id))?
+        .fullname;
+    let sections: Vec<CourseSection> = call_web_service(&client, &site_url, token,
+        "core_course_get_contents", &[("courseid", course_id.to_string())]).await?;
+
+    let mut course_files = vec![];
+    for section in sections {
+        for module in section.modules {
+            let is_folder = module.modname == "folder";
+            for content in module.contents {
+                let url = match (content.content_type.as_str(), content.fileurl) {
+                    ("file", Some(file_url)) => session_file_url(Url::parse(&file_url)?).to_string(),
+                    ("url", Some(url)) => url,
+                    _ => continue
+                };
+                if let LinkTarget::Resource(resource) = classify_link(url) {
+                    let metadata = CourseFileMetadata::MoodleActivity {
+                        lecture_title: lecture_title.clone(),
+                        section_title: section.name.clone(),
+                        activity_title: module.name.clone(),
+                        file_size: content.filesize.filter(|size| *size > 0),
+                        time_modified: content.timemodified
+                            .and_then(|timestamp| chrono::Utc.timestamp_opt(timestamp, 0).single()),
+                        // Folder contents have paths like `/directory/subdirectory/`
+                        folder_path: content.filepath.filter(|_| is_folder)
+                            .map(|filepath| filepath.trim_matches('/').to_owned())
+                    };
+                    course_files.push(CourseFile { metadata, resource });
+                }
+            }
+        }
+    }
+    Ok(course_files)
+}
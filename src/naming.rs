@@ -0,0 +1,525 @@
+use std::{collections::HashSet, fmt::Display, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use reqwest::{Url, header};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc, format::{Item, StrftimeItems}};
+use simple_error::simple_error;
+
+use crate::{GenericResult, data::{CourseFile, CourseFileDownload, CourseFileMetadata, FilenameCollisionPolicy, url_hash},
+    download::temporary_sibling_path};
+
+/// Decides where a downloaded file is stored, given the filename suggested by the server (or derived from the url)
+pub type TargetPathResolver = Box<dyn FnOnce(&str) -> GenericResult<PathBuf> + Send>;
+
+/// Used if neither the server nor the url suggest a usable filename
+const DEFAULT_FILENAME: &str = "download";
+
+/// Formats tried when interpreting a `date_time_string` for the `{date:...}` template field
+const DATE_TIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%d.%m.%Y %H:%M", "%A, %B %d %Y, %H:%M", "%B %d %Y, %H:%M"];
+const DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%d.%m.%Y", "%A, %B %d %Y", "%B %d %Y"];
+
+/// Characters that are not allowed in file names on common file systems
+const PATH_UNSAFE_CHARACTERS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// File extensions for common MIME types of lecture materials
+const MIME_TYPE_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-tar", "tar"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/json", "json"),
+    ("application/msword", "doc"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.oasis.opendocument.text", "odt"),
+    ("application/vnd.oasis.opendocument.presentation", "odp"),
+    ("text/plain", "txt"),
+    ("text/csv", "csv"),
+    ("text/html", "html"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/svg+xml", "svg"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("video/mp2t", "ts"),
+    ("audio/mpeg", "mp3"),
+];
+
+/// Determines the filename for a download response: the `Content-Disposition` filename if present,
+/// otherwise the last segment of the (final, after redirects) url. If the filename has no extension,
+/// one is derived from the `Content-Type`.
+pub fn response_filename(resp: &reqwest::Response) -> String {
+    let header_value = |name| resp.headers().get(name).and_then(|value: &header::HeaderValue| value.to_str().ok());
+    let filename = header_value(header::CONTENT_DISPOSITION)
+        .and_then(content_disposition_filename)
+        .or_else(|| url_filename(resp.url()))
+        .unwrap_or_else(|| DEFAULT_FILENAME.to_owned());
+
+    if filename.contains('.') {
+        return filename;
+    }
+    match header_value(header::CONTENT_TYPE).and_then(mime_type_extension) {
+        Some(extension) => format!("{}.{}", filename, extension),
+        None => filename
+    }
+}
+
+/// Extracts the filename from a `Content-Disposition` header value. The RFC 5987 encoded `filename*`
+/// parameter is preferred over the plain `filename` parameter.
+pub fn content_disposition_filename(content_disposition: &str) -> Option<String> {
+    let mut filename = None;
+    let mut extended_filename = None;
+    for parameter in split_parameters(content_disposition).into_iter().skip(1) {
+        let (name, value) = match parameter.split_once('=') {
+            Some((name, value)) => (name.trim().to_lowercase(), value.trim()),
+            None => continue
+        };
+        match name.as_str() {
+            "filename" => { filename = Some(unquote(value)); },
+            "filename*" => { extended_filename = decode_extended_value(value); },
+            _ => {}
+        }
+    }
+    extended_filename.or(filename).and_then(|filename| sanitize_server_filename(&filename))
+}
+
+/// The percent-decoded last path segment of `url`, if there is a non-empty one
+pub fn url_filename(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    let decoded = urlencoding::decode(segment).map(|s| s.into_owned())
+        // Not valid UTF-8 after decoding: keep what can be read
+        .unwrap_or_else(|_| String::from_utf8_lossy(&urlencoding::decode_binary(segment.as_bytes())).into_owned());
+    sanitize_server_filename(&decoded)
+}
+
+fn mime_type_extension(content_type: &str) -> Option<&'static str> {
+    let mime_type = content_type.split(';').next()?.trim().to_lowercase();
+    MIME_TYPE_EXTENSIONS.iter().find(|(known_type, _)| *known_type == mime_type).map(|(_, extension)| *extension)
+}
+
+// Splits a header value at semicolons that are not inside quotes
+fn split_parameters(value: &str) -> Vec<&str> {
+    let mut parameters = vec![];
+    let (mut start, mut in_quotes, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => { escaped = false; },
+            '\\' if in_quotes => { escaped = true; },
+            '"' => { in_quotes = !in_quotes; },
+            ';' if !in_quotes => { parameters.push(&value[start..i]); start = i + 1; },
+            _ => {}
+        }
+    }
+    parameters.push(&value[start..]);
+    parameters
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => value.to_owned()
+    }
+}
+
+// Decodes an RFC 5987 extended value like `UTF-8''%E2%82%AC%20rates.pdf`
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes = urlencoding::decode_binary(encoded.as_bytes());
+    match charset.to_lowercase().as_str() {
+        "utf-8" => String::from_utf8(bytes.into_owned()).ok(),
+        // ISO-8859-1 bytes map directly to the first 256 unicode code points
+        "iso-8859-1" => Some(bytes.iter().map(|&b| b as char).collect()),
+        _ => None
+    }
+}
+
+// Server-provided names must not escape the target directory, so only the last path component is kept
+fn sanitize_server_filename(filename: &str) -> Option<String> {
+    let filename = filename.rsplit(['/', '\\']).next()?.trim();
+    if filename.is_empty() || filename == "." || filename == ".." {
+        None
+    } else {
+        Some(filename.to_owned())
+    }
+}
+
+/// Makes `name` usable as a single path component: path-unsafe and control characters are replaced by `_`,
+/// surrounding whitespace and dots are removed.
+pub fn sanitize_path_component(name: &str) -> String {
+    name.chars()
+        .map(|c| if PATH_UNSAFE_CHARACTERS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect::<String>()
+        .trim_matches(|c: char| c.is_whitespace() || c == '.')
+        .to_owned()
+}
+
+/// Expands a filename template like `{section_title}/{activity_title}.{ext}` into a relative path.
+/// Available fields are those of `CourseFileMetadata` (`lecture_title`, `section_title`, `activity_title`,
+/// `video_title`, `date_time_string`, `page_title`, `link_text`, `folder_path`), `filename` and `ext` (the filename suggested by the server and its
+/// extension), and `date:<format>`, the file's date in `strftime` format (e.g. `{date:%Y-%m-%d}`).
+/// Fields that do not exist for a file's kind of metadata expand to an empty string. Field values are sanitized,
+/// while `/` in the template itself and in `folder_path` separates directories.
+pub fn expand_filename_template(template: &str, metadata: &CourseFileMetadata, filename: &str,
+    discovery_time: DateTime<Utc>) -> GenericResult<PathBuf>
+{
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(field_start) = rest.find('{') {
+        expanded.push_str(&rest[..field_start]);
+        let field_end = rest[field_start..].find('}')
+            .ok_or(simple_error!("Unclosed '{{' in filename template '{}'", template))? + field_start;
+        let field = &rest[field_start+1..field_end];
+        let value = template_field_value(field, metadata, filename, discovery_time)
+            .ok_or(simple_error!("Unknown field '{}' in filename template '{}'", field, template))??;
+        if field == "folder_path" {
+            expanded.push_str(&value.split('/').map(sanitize_path_component).collect::<Vec<_>>().join("/"));
+        } else {
+            expanded.push_str(&sanitize_path_component(&value));
+        }
+        rest = &rest[field_end+1..];
+    }
+    expanded.push_str(rest);
+
+    // Empty fields may leave empty path components (e.g. a missing section title), which are dropped
+    let path = expanded.split('/')
+        .map(str::trim)
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        return Err(simple_error!("Filename template '{}' expands to an empty path", template).into());
+    }
+    Ok(path)
+}
+
+// Returns `None` for unknown fields
+fn template_field_value(field: &str, metadata: &CourseFileMetadata, filename: &str, discovery_time: DateTime<Utc>)
+    -> Option<GenericResult<String>>
+{
+    if let Some(date_format) = field.strip_prefix("date:") {
+        return Some(format_date(metadata, discovery_time, date_format));
+    }
+    let value = match (field, metadata) {
+        ("filename", _) => filename,
+        ("ext", _) => filename.rsplit_once('.').map_or("", |(_, extension)| extension),
+        ("lecture_title", CourseFileMetadata::TumLiveStream { lecture_title, .. })
+        | ("lecture_title", CourseFileMetadata::MoodleActivity { lecture_title, .. }) => lecture_title,
+        ("video_title", CourseFileMetadata::TumLiveStream { video_title, .. }) => video_title,
+        ("date_time_string", CourseFileMetadata::TumLiveStream { date_time_string, .. }) => date_time_string,
+        ("section_title", CourseFileMetadata::MoodleActivity { section_title, .. }) => section_title,
+        ("activity_title", CourseFileMetadata::MoodleActivity { activity_title, .. }) => activity_title,
+        ("folder_path", CourseFileMetadata::MoodleActivity { folder_path: Some(folder_path), .. }) => folder_path,
+        ("page_title", CourseFileMetadata::WebsiteLink { page_title, .. }) => page_title,
+        ("link_text", CourseFileMetadata::WebsiteLink { link_text, .. }) => link_text,
+        ("lecture_title", _) | ("video_title", _) | ("date_time_string", _) | ("section_title", _) | ("activity_title", _)
+        | ("page_title", _) | ("link_text", _) | ("folder_path", _) => "",
+        _ => return None
+    };
+    Some(Ok(value.to_owned()))
+}
+
+// Formats the date of a file: the date from its metadata if it can be interpreted, otherwise its discovery date
+fn format_date(metadata: &CourseFileMetadata, discovery_time: DateTime<Utc>, date_format: &str) -> GenericResult<String> {
+    // chrono panics when displaying invalid format strings, so they are checked up front
+    if StrftimeItems::new(date_format).any(|item| matches!(item, Item::Error)) {
+        return Err(simple_error!("Invalid date format '{}' in filename template", date_format).into());
+    }
+    let metadata_date_time = match metadata {
+        CourseFileMetadata::TumLiveStream { date_time_string, .. } => parse_date_time(date_time_string.trim()),
+        CourseFileMetadata::MoodleActivity { time_modified, .. } =>
+            time_modified.map(|time_modified| time_modified.with_timezone(&chrono::Local).naive_local()),
+        _ => None
+    };
+    let date_time = metadata_date_time.unwrap_or_else(|| discovery_time.with_timezone(&chrono::Local).naive_local());
+    Ok(date_time.format(date_format).to_string())
+}
+
+/// The point in time a file was published or last modified according to its metadata, if known.
+/// Dates shown on TUM Live are in local time.
+pub fn metadata_date_time(metadata: &CourseFileMetadata) -> Option<DateTime<Utc>> {
+    match metadata {
+        CourseFileMetadata::TumLiveStream { date_time_string, .. } => parse_date_time(date_time_string.trim())
+            .and_then(|date_time| chrono::Local.from_local_datetime(&date_time).earliest())
+            .map(|date_time| date_time.with_timezone(&Utc)),
+        CourseFileMetadata::MoodleActivity { time_modified, .. } => *time_modified,
+        _ => None
+    }
+}
+
+fn parse_date_time(date_time_string: &str) -> Option<NaiveDateTime> {
+    DATE_TIME_FORMATS.iter().find_map(|format| NaiveDateTime::parse_from_str(date_time_string, format).ok())
+        .or_else(|| DATE_FORMATS.iter().find_map(|format| NaiveDate::parse_from_str(date_time_string, format).ok())
+            .and_then(|date| date.and_hms_opt(0, 0, 0)))
+}
+
+/// The directory that files of a folder activity are stored in if there is no filename template, relative to the
+/// course directory: the folder's name followed by the directories inside the folder. `None` for other files.
+pub fn folder_directory(metadata: &CourseFileMetadata) -> Option<PathBuf> {
+    match metadata {
+        CourseFileMetadata::MoodleActivity { activity_title, folder_path: Some(folder_path), .. } => {
+            Some(std::iter::once(activity_title.as_str()).chain(folder_path.split('/'))
+                .map(sanitize_path_component)
+                .filter(|component| !component.is_empty())
+                .collect())
+        },
+        _ => None
+    }
+}
+
+/// Returned when a download is not performed because its target path is taken and the collision policy is `Skip`
+#[derive(Debug)]
+pub struct FilenameCollisionSkipped {
+    pub path: PathBuf
+}
+
+impl Display for FilenameCollisionSkipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Skipped: {} already exists", self.path.display())
+    }
+}
+
+impl std::error::Error for FilenameCollisionSkipped {}
+
+/// The target paths that downloads of the current run have claimed, to detect collisions between them
+#[derive(Clone, Default)]
+pub struct ClaimedPaths {
+    paths: Arc<Mutex<HashSet<PathBuf>>>
+}
+
+impl ClaimedPaths {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claims the path to store the file from `url` at. `path` is used if there is no file and no other claim yet,
+    /// otherwise the collision is resolved according to `policy`. The file at `replaced_path` (the current version
+    /// of a changed file) is replaced by the download, so it is no collision.
+    pub fn claim(&self, path: PathBuf, replaced_path: Option<&Path>, policy: &FilenameCollisionPolicy, url: &str)
+        -> GenericResult<PathBuf>
+    {
+        let mut claimed_paths = self.paths.lock().unwrap();
+        let is_taken = |path: &Path| (path.exists() && Some(path) != replaced_path) || claimed_paths.contains(path);
+        let path = if !is_taken(&path) {
+            path
+        } else {
+            match policy {
+                FilenameCollisionPolicy::NumericSuffix => first_free_numbered_path(&path, is_taken),
+                FilenameCollisionPolicy::ResourceHashSuffix => {
+                    let hash_suffix = format!("-{:016x}", url_hash(url));
+                    let hashed_path = path_with_suffix(&path, &hash_suffix[..9]);
+                    if is_taken(&hashed_path) { first_free_numbered_path(&hashed_path, is_taken) } else { hashed_path }
+                },
+                FilenameCollisionPolicy::Skip => return Err(FilenameCollisionSkipped { path }.into()),
+                FilenameCollisionPolicy::Overwrite => path
+            }
+        };
+        claimed_paths.insert(path.clone());
+        Ok(path)
+    }
+}
+
+fn first_free_numbered_path<F>(path: &Path, is_taken: F) -> PathBuf
+    where F: Fn(&Path) -> bool
+{
+    (1..).map(|i| path_with_suffix(path, &format!(" ({})", i)))
+        .find(|numbered_path| !is_taken(numbered_path))
+        .unwrap()
+}
+
+/// The path an earlier version of a file is kept at, e.g. `slides.v1.pdf` for version 1 of `slides.pdf`.
+/// Versions whose path is taken (e.g. by a file of the same name from elsewhere) are skipped.
+pub fn versioned_path(path: &Path, first_version: usize) -> PathBuf {
+    (first_version..).map(|version| path_with_suffix(path, &format!(".v{}", version)))
+        .find(|versioned_path| !versioned_path.exists())
+        .unwrap()
+}
+
+/// The path a new version of the changed file at `path` is downloaded to, while the current version is kept
+pub fn replacement_path(path: &Path) -> PathBuf {
+    temporary_sibling_path(path, ".new")
+}
+
+/// Once the new version of a changed file is complete at `new_path`, keeps the version it replaces as a previous
+/// version (see `versioned_path`) and moves the new version to its place. Returns the path of the new version.
+pub fn keep_replaced_version(file: &mut CourseFileDownload<CourseFile>, new_path: PathBuf) -> GenericResult<PathBuf> {
+    let replaced_path = match &file.replaced_path {
+        Some(replaced_path) => replaced_path.clone(),
+        None => return Ok(new_path)
+    };
+    if replaced_path.exists() {
+        let version_path = versioned_path(&replaced_path, file.previous_versions.len() + 1);
+        std::fs::rename(&replaced_path, &version_path)?;
+        file.previous_versions.push(version_path);
+    }
+    let new_path = if new_path == replacement_path(&replaced_path) {
+        std::fs::rename(&new_path, &replaced_path)?;
+        replaced_path
+    } else {
+        new_path
+    };
+    file.replaced_path = None;
+    Ok(new_path)
+}
+
+// Inserts `suffix` between file stem and extension, e.g. `slides.pdf` -> `slides (1).pdf`
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+    file_name.push(suffix);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moodle_activity(folder_path: Option<&str>) -> CourseFileMetadata {
+        CourseFileMetadata::MoodleActivity {
+            lecture_title: "Analysis 1".to_owned(),
+            section_title: "Week 1: Basics".to_owned(),
+            activity_title: "Exercise sheet".to_owned(),
+            file_size: None,
+            time_modified: None,
+            folder_path: folder_path.map(str::to_owned)
+        }
+    }
+
+    fn discovery_time() -> DateTime<Utc> {
+        Utc.ymd(2023, 4, 17).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn takes_filename_from_content_disposition() {
+        assert_eq!(content_disposition_filename("attachment; filename=\"slides 01.pdf\"").as_deref(), Some("slides 01.pdf"));
+        assert_eq!(content_disposition_filename("inline; filename=notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(content_disposition_filename("attachment; filename=\"a \\\"quoted\\\"; name.pdf\"").as_deref(),
+            Some("a \"quoted\"; name.pdf"));
+        assert_eq!(content_disposition_filename("attachment"), None);
+    }
+
+    #[test]
+    fn prefers_extended_content_disposition_filename() {
+        assert_eq!(content_disposition_filename("attachment; filename=\"EUR rates.pdf\"; filename*=UTF-8''%E2%82%AC%20rates.pdf")
+            .as_deref(), Some("€ rates.pdf"));
+        assert_eq!(content_disposition_filename("attachment; filename*=iso-8859-1'de'%DCbung.pdf").as_deref(), Some("Übung.pdf"));
+        // Unsupported charsets fall back to the plain filename
+        assert_eq!(content_disposition_filename("attachment; filename=plain.pdf; filename*=x-unknown''other.pdf").as_deref(),
+            Some("plain.pdf"));
+    }
+
+    #[test]
+    fn content_disposition_filename_cannot_escape_directory() {
+        assert_eq!(content_disposition_filename("attachment; filename=\"../../etc/passwd\"").as_deref(), Some("passwd"));
+        assert_eq!(content_disposition_filename("attachment; filename=\"C:\\\\Users\\\\evil.exe\"").as_deref(), Some("evil.exe"));
+        assert_eq!(content_disposition_filename("attachment; filename=\"..\""), None);
+    }
+
+    #[test]
+    fn takes_filename_from_url() {
+        let url = Url::parse("https://example.com/files/Blatt%2001.pdf?forcedownload=1").unwrap();
+        assert_eq!(url_filename(&url).as_deref(), Some("Blatt 01.pdf"));
+        assert_eq!(url_filename(&Url::parse("https://example.com/files/").unwrap()), None);
+    }
+
+    #[test]
+    fn expands_metadata_fields_into_sanitized_path() {
+        let path = expand_filename_template("{lecture_title}/{section_title}/{activity_title}.{ext}", &moodle_activity(None),
+            "sheet01.pdf", discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("Analysis 1/Week 1_ Basics/Exercise sheet.pdf"));
+    }
+
+    #[test]
+    fn folder_path_field_keeps_directories() {
+        let path = expand_filename_template("{activity_title}/{folder_path}/{filename}", &moodle_activity(Some("Week 1/Solutions")),
+            "sheet01.pdf", discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("Exercise sheet/Week 1/Solutions/sheet01.pdf"));
+    }
+
+    #[test]
+    fn missing_fields_leave_no_empty_directories() {
+        let metadata = CourseFileMetadata::WebsiteLink { page_title: "Lecture".to_owned(), link_text: "Slides".to_owned() };
+        let path = expand_filename_template("{section_title}/{page_title}/{link_text}.{ext}", &metadata, "slides.pdf",
+            discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("Lecture/Slides.pdf"));
+        // Traversal in the template itself is dropped as well
+        let path = expand_filename_template("../{filename}", &metadata, "slides.pdf", discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("slides.pdf"));
+    }
+
+    #[test]
+    fn formats_dates_from_metadata_or_discovery_time() {
+        let stream = CourseFileMetadata::TumLiveStream { lecture_title: "Analysis 1".to_owned(), video_title: "Lecture 3".to_owned(),
+            date_time_string: "2023-05-02 10:15".to_owned() };
+        let path = expand_filename_template("{date:%Y-%m-%d} {video_title}.mp4", &stream, "playlist.m3u8", discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("2023-05-02 Lecture 3.mp4"));
+        let path = expand_filename_template("{date:%Y}/{filename}", &moodle_activity(None), "sheet01.pdf", discovery_time()).unwrap();
+        assert_eq!(path, PathBuf::from("2023/sheet01.pdf"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let metadata = moodle_activity(None);
+        assert!(expand_filename_template("{unknown}", &metadata, "a.pdf", discovery_time()).is_err());
+        assert!(expand_filename_template("{filename", &metadata, "a.pdf", discovery_time()).is_err());
+        assert!(expand_filename_template("{date:%Q}", &metadata, "a.pdf", discovery_time()).is_err());
+        assert!(expand_filename_template("{section_title}", &metadata, "a.pdf", discovery_time()).is_ok());
+        let metadata = CourseFileMetadata::WebsiteLink { page_title: String::new(), link_text: String::new() };
+        assert!(expand_filename_template("{link_text}", &metadata, "a.pdf", discovery_time()).is_err());
+    }
+
+    #[test]
+    fn claimed_paths_resolve_collisions_by_policy() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("slides.pdf");
+        std::fs::write(&path, b"existing").unwrap();
+        let claimed_paths = ClaimedPaths::new();
+        let claim = |policy, url| claimed_paths.claim(path.clone(), None, &policy, url);
+
+        assert_eq!(claim(FilenameCollisionPolicy::NumericSuffix, "https://example.com/a").unwrap(),
+            directory.path().join("slides (1).pdf"));
+        // The numbered path is claimed now, so the next download gets another one
+        assert_eq!(claim(FilenameCollisionPolicy::NumericSuffix, "https://example.com/b").unwrap(),
+            directory.path().join("slides (2).pdf"));
+        assert_eq!(claim(FilenameCollisionPolicy::ResourceHashSuffix, "https://example.com/c").unwrap(),
+            directory.path().join(format!("slides-{:08x}.pdf", url_hash("https://example.com/c") >> 32)));
+        assert!(claim(FilenameCollisionPolicy::Skip, "https://example.com/d").unwrap_err().is::<FilenameCollisionSkipped>());
+        assert_eq!(claim(FilenameCollisionPolicy::Overwrite, "https://example.com/e").unwrap(), path);
+    }
+
+    #[test]
+    fn claimed_paths_detect_collisions_between_downloads() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("video.mp4");
+        let claimed_paths = ClaimedPaths::new();
+        assert_eq!(claimed_paths.claim(path.clone(), None, &FilenameCollisionPolicy::Skip, "https://example.com/a").unwrap(), path);
+        assert!(claimed_paths.claim(path.clone(), None, &FilenameCollisionPolicy::Skip, "https://example.com/b").is_err());
+    }
+
+    #[test]
+    fn replaced_file_is_no_collision() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("slides.pdf");
+        std::fs::write(&path, b"old version").unwrap();
+        let claimed_paths = ClaimedPaths::new();
+        assert_eq!(claimed_paths.claim(path.clone(), Some(&path), &FilenameCollisionPolicy::Skip, "https://example.com/a").unwrap(),
+            path);
+    }
+
+    #[test]
+    fn folder_files_are_placed_in_activity_directory() {
+        assert_eq!(folder_directory(&moodle_activity(Some("Sheet 1/solutions"))),
+            Some(PathBuf::from("Exercise sheet/Sheet 1/solutions")));
+        assert_eq!(folder_directory(&moodle_activity(Some(""))), Some(PathBuf::from("Exercise sheet")));
+        // Path components coming from the server cannot escape the activity directory
+        assert_eq!(folder_directory(&moodle_activity(Some("../a:b/."))), Some(PathBuf::from("Exercise sheet/a_b")));
+        assert_eq!(folder_directory(&moodle_activity(None)), None);
+    }
+}
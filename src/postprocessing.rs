@@ -1,5 +1,6 @@
-use crate::{GenericResult, data::{CourseFileDownload, CourseFile, DownloadState, PostprocessingStep}};
-use std::process::{Command, Stdio};
+use crate::{GenericResult, data::{CourseFileDownload, CourseFile, DownloadState, PostprocessingStep},
+    download::{persist_file, temporary_sibling_path}};
+use std::{path::Path, process::{Command, Stdio}};
 use tempfile;
 use simple_error::simple_error;
 
@@ -42,6 +43,47 @@ fn ffmpeg_reencode(video: &CourseFileDownload<CourseFile>, target_fps: u32) -> G
     if !output_status?.success() {
         return Err(simple_error!("Postprocessing failed: ffmpeg returned non-zero status code.").into());
     }
-    std::fs::copy(output_path_str, input_path_str)?;
+    // Replace the input in one step, s.t. an interruption cannot leave a partially copied video behind
+    let temporary_path = temporary_sibling_path(input_path, ".part");
+    std::fs::copy(output_path_str, &temporary_path)?;
+    persist_file(&temporary_path, input_path)?;
+    Ok(())
+}
+
+/// Remuxes a (concatenated) MPEG transport stream into an MP4 container. ffmpeg only copies the streams,
+/// nothing is re-encoded. The result is validated using ffprobe.
+pub fn remux_to_mp4(input_path: &Path, output_path: &Path) -> GenericResult<()> {
+    let output_status = Command::new("ffmpeg")
+        .arg("-y")
+        .arg("-i").arg(input_path)
+        .args(["-codec", "copy",
+            "-movflags", "+faststart",
+            "-f", "mp4"])
+        .arg(output_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !output_status.success() {
+        return Err(simple_error!("Remuxing failed: ffmpeg returned non-zero status code.").into());
+    }
+    validate_video_file(output_path)
+}
+
+/// Checks with ffprobe that the file at `path` is a readable video with a positive duration
+pub fn validate_video_file(path: &Path) -> GenericResult<()> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error",
+            "-show_entries", "format=duration",
+            "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(simple_error!("Validation failed: ffprobe returned non-zero status code.").into());
+    }
+    let duration = String::from_utf8_lossy(&output.stdout).trim().parse::<f64>().unwrap_or(0.0);
+    if duration <= 0.0 {
+        return Err(simple_error!("Validation failed: ffprobe reports no positive duration.").into());
+    }
     Ok(())
 }
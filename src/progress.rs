@@ -0,0 +1,179 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+/// Progress of a single download, or of all downloads together
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    pub received_bytes: u64,
+    /// `None` if the size is not known (yet)
+    pub total_bytes: Option<u64>,
+    pub bytes_per_second: f64,
+    pub eta: Option<Duration>
+}
+
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    pub name: String,
+    pub progress: TransferProgress,
+    /// `false` while the download is still waiting for its turn
+    pub started: bool,
+    pub finished: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgressReport {
+    pub files: Vec<FileProgress>,
+    pub aggregate: TransferProgress
+}
+
+/// Receives progress reports while downloads are running
+pub trait ProgressObserver: Send + Sync {
+    fn report(&self, report: &ProgressReport);
+}
+
+struct TrackedFile {
+    name: String,
+    received_bytes: u64,
+    total_bytes: Option<u64>,
+    // Bytes that were already present when the download started (e.g. when resuming), excluded from the throughput
+    initial_bytes: u64,
+    start_time: Instant,
+    started: bool,
+    finished: bool
+}
+
+impl TrackedFile {
+    fn progress(&self, now: Instant) -> TransferProgress {
+        let elapsed_seconds = now.duration_since(self.start_time).as_secs_f64();
+        let bytes_per_second = if self.finished || elapsed_seconds <= 0.0 { 0.0 }
+            else { (self.received_bytes - self.initial_bytes) as f64 / elapsed_seconds };
+        TransferProgress {
+            received_bytes: self.received_bytes,
+            total_bytes: self.total_bytes,
+            bytes_per_second,
+            eta: estimate_remaining_time(self.received_bytes, self.total_bytes, bytes_per_second)
+        }
+    }
+}
+
+fn estimate_remaining_time(received_bytes: u64, total_bytes: Option<u64>, bytes_per_second: f64) -> Option<Duration> {
+    let remaining_bytes = total_bytes?.saturating_sub(received_bytes);
+    if remaining_bytes == 0 {
+        Some(Duration::ZERO)
+    } else if bytes_per_second > 0.0 {
+        Some(Duration::from_secs_f64(remaining_bytes as f64 / bytes_per_second))
+    } else {
+        None
+    }
+}
+
+struct TrackerState {
+    files: Vec<TrackedFile>,
+    last_report_time: Option<Instant>
+}
+
+/// Collects the progress of all downloads and reports it to an observer, at most once per `report_interval`
+/// (and whenever a download finishes)
+pub struct ProgressTracker {
+    observer: Box<dyn ProgressObserver>,
+    report_interval: Duration,
+    state: Mutex<TrackerState>
+}
+
+impl ProgressTracker {
+    pub fn new(observer: Box<dyn ProgressObserver>, report_interval: Duration) -> Arc<Self> {
+        Arc::new(ProgressTracker {
+            observer,
+            report_interval,
+            state: Mutex::new(TrackerState { files: vec![], last_report_time: None })
+        })
+    }
+
+    /// Registers a new download, which is reported under `name`
+    pub fn start_download(self: &Arc<Self>, name: String) -> DownloadProgress {
+        let mut state = self.state.lock().unwrap();
+        state.files.push(TrackedFile {
+            name, received_bytes: 0, total_bytes: None, initial_bytes: 0, start_time: Instant::now(), started: false, finished: false
+        });
+        DownloadProgress { tracker: Some((self.clone(), state.files.len() - 1)) }
+    }
+
+    // Applies `update` to the file with index `file_index` and reports the new progress if it is time to
+    fn update<F>(&self, file_index: usize, force_report: bool, update: F)
+        where F: FnOnce(&mut TrackedFile)
+    {
+        let report = {
+            let mut state = self.state.lock().unwrap();
+            update(&mut state.files[file_index]);
+            let now = Instant::now();
+            let report_due = state.last_report_time
+                .is_none_or(|last_report_time| now.duration_since(last_report_time) >= self.report_interval);
+            if !(force_report || report_due) {
+                return;
+            }
+            state.last_report_time = Some(now);
+            build_report(&state.files, now)
+        };
+        // The observer is called without holding the lock, s.t. it can take its time
+        self.observer.report(&report);
+    }
+}
+
+fn build_report(files: &[TrackedFile], now: Instant) -> ProgressReport {
+    let files = files.iter()
+        .map(|file| FileProgress {
+            name: file.name.clone(), progress: file.progress(now), started: file.started, finished: file.finished
+        })
+        .collect::<Vec<_>>();
+    let received_bytes = files.iter().map(|file| file.progress.received_bytes).sum();
+    // The total size is only known if it is known for every file
+    let total_bytes = files.iter().map(|file| file.progress.total_bytes).sum();
+    let bytes_per_second = files.iter().map(|file| file.progress.bytes_per_second).sum();
+    let aggregate = TransferProgress {
+        received_bytes, total_bytes, bytes_per_second,
+        eta: estimate_remaining_time(received_bytes, total_bytes, bytes_per_second)
+    };
+    ProgressReport { files, aggregate }
+}
+
+/// Handle through which a download reports its progress. The download counts as finished when the handle is dropped.
+/// A `DownloadProgress::default()` handle does not report anywhere.
+#[derive(Default)]
+pub struct DownloadProgress {
+    tracker: Option<(Arc<ProgressTracker>, usize)>
+}
+
+impl DownloadProgress {
+    /// Marks the download as started, with its expected size and the bytes already present (when resuming a download)
+    pub fn start(&self, total_bytes: Option<u64>, initial_bytes: u64) {
+        if let Some((tracker, file_index)) = &self.tracker {
+            tracker.update(*file_index, false, |file| {
+                file.total_bytes = total_bytes;
+                file.received_bytes = initial_bytes;
+                file.initial_bytes = initial_bytes;
+                file.start_time = Instant::now();
+                file.started = true;
+            });
+        }
+    }
+
+    /// Updates the expected size, e.g. when it can be estimated better while downloading
+    pub fn set_total_bytes(&self, total_bytes: Option<u64>) {
+        if let Some((tracker, file_index)) = &self.tracker {
+            tracker.update(*file_index, false, |file| { file.total_bytes = total_bytes; });
+        }
+    }
+
+    pub fn add_received_bytes(&self, bytes: u64) {
+        if let Some((tracker, file_index)) = &self.tracker {
+            tracker.update(*file_index, false, |file| { file.received_bytes += bytes; });
+        }
+    }
+}
+
+impl Drop for DownloadProgress {
+    fn drop(&mut self) {
+        if let Some((tracker, file_index)) = &self.tracker {
+            tracker.update(*file_index, true, |file| { file.finished = true; });
+        }
+    }
+}
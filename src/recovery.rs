@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+
+use crate::{GenericResult, data::{CourseFile, CourseFileDownload, CourseFileResource, DownloadState},
+    download::{partial_download_paths, segment_directory_path, temporary_sibling_path}, naming::keep_replaced_version, postprocessing::validate_video_file};
+
+/// What the recovery pass did with a file that was left behind by an interrupted run
+#[derive(Debug)]
+pub enum RecoveryAction {
+    /// The download was interrupted and is requested again
+    ResetToRequested,
+    /// The download had actually finished, the file was found complete at this path
+    Finished(PathBuf),
+    /// Postprocessing was interrupted and will be performed again
+    RequeuedPostprocessing
+}
+
+/// Recovers a file whose download or postprocessing was interrupted (e.g. because the process was killed),
+/// i.e. that is still in state `Running` or `PostprocessingPending` on startup. Files on disk are verified:
+/// leftover temporary files and videos that turn out to be broken are removed, and the download is requested again.
+/// Partial downloads that can be resumed are kept in `download_directory`.
+pub fn recover_interrupted_file(file: &mut CourseFileDownload<CourseFile>, download_directory: &Path, needs_postprocessing: bool)
+    -> GenericResult<Option<RecoveryAction>>
+{
+    let action = match &file.download_state {
+        DownloadState::Running(path) => {
+            remove_if_exists(&temporary_sibling_path(path, ".part"))?;
+            // HLS segments cannot be resumed, a new download fetches the current playlist again
+            let segment_directory = segment_directory_path(path);
+            if segment_directory.is_dir() {
+                std::fs::remove_dir_all(segment_directory)?;
+            }
+            // HLS streams are only moved to their path when complete, other downloads are written to a `.part` file
+            // first. Their path is recorded as soon as it is known, before that the state holds the download directory.
+            let finished = match &file.file.resource {
+                CourseFileResource::HlsStream { .. } => path.is_file() && !is_broken_video(path),
+                resource => path.is_file() && !partial_download_paths(download_directory, resource.url()).0.exists()
+            };
+            if finished {
+                let path = keep_replaced_version(file, path.clone())?;
+                file.download_state = if needs_postprocessing { DownloadState::PostprocessingPending }
+                    else { DownloadState::Completed }(path.clone());
+                file.download_time = Some(chrono::Utc::now());
+                RecoveryAction::Finished(path)
+            } else {
+                // A `.part` file without its progress file cannot be resumed
+                if !matches!(file.file.resource, CourseFileResource::HlsStream { .. }) {
+                    let (part_path, progress_path) = partial_download_paths(download_directory, file.file.resource.url());
+                    if !progress_path.exists() {
+                        remove_if_exists(&part_path)?;
+                    }
+                }
+                // Older versions wrote downloads directly to their path, possibly leaving a truncated video behind
+                if file.file.is_video() && path.is_file() && is_broken_video(path) {
+                    std::fs::remove_file(path)?;
+                }
+                file.download_state = DownloadState::Requested;
+                RecoveryAction::ResetToRequested
+            }
+        },
+        DownloadState::PostprocessingPending(path) => {
+            remove_if_exists(&temporary_sibling_path(path, ".part"))?;
+            if !path.exists() || is_broken_video(path) {
+                remove_if_exists(path)?;
+                file.download_state = DownloadState::Requested;
+                RecoveryAction::ResetToRequested
+            } else {
+                // The state already makes postprocessing run again
+                RecoveryAction::RequeuedPostprocessing
+            }
+        },
+        _ => return Ok(None)
+    };
+    Ok(Some(action))
+}
+
+// Only a video that ffprobe could examine and rejected is broken. If ffprobe cannot be run, videos are assumed to be fine.
+fn is_broken_video(path: &Path) -> bool {
+    match validate_video_file(path) {
+        Ok(()) => false,
+        Err(error) => !error.is::<std::io::Error>()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> GenericResult<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::CourseFileMetadata;
+
+    const URL: &str = "https://www.moodle.tum.de/pluginfile.php/123/mod_resource/content/1/sheet.pdf";
+
+    fn document(download_state: DownloadState) -> CourseFileDownload<CourseFile> {
+        CourseFileDownload {
+            file: CourseFile {
+                resource: CourseFileResource::Document { url: URL.to_owned(), file_extension: Some("pdf".to_owned()) },
+                metadata: CourseFileMetadata::MoodleActivity { lecture_title: "Analysis 1".to_owned(), section_title: "Week 1".to_owned(),
+                    activity_title: "Sheet".to_owned(), file_size: None, time_modified: None, folder_path: None }
+            },
+            available: true,
+            download_state,
+            discovery_time: chrono::Utc::now(),
+            download_time: None,
+            auto_requested: false,
+            hls_variant: None,
+            validators: None,
+            previous_versions: vec![],
+            replaced_path: None,
+            failure: None
+        }
+    }
+
+    // A download directory inside a temporary directory, s.t. hidden siblings of it are cleaned up as well
+    fn download_directory() -> (tempfile::TempDir, PathBuf) {
+        let temporary_directory = tempfile::tempdir().unwrap();
+        let download_directory = temporary_directory.path().join("documents");
+        std::fs::create_dir(&download_directory).unwrap();
+        (temporary_directory, download_directory)
+    }
+
+    #[test]
+    fn keeps_resumable_partial_download() {
+        let (_temporary_directory, download_directory) = download_directory();
+        let (part_path, progress_path) = partial_download_paths(&download_directory, URL);
+        std::fs::write(&part_path, b"partial").unwrap();
+        std::fs::write(&progress_path, b"{}").unwrap();
+
+        let mut file = document(DownloadState::Running(download_directory.clone()));
+        let action = recover_interrupted_file(&mut file, &download_directory, false).unwrap();
+        assert!(matches!(action, Some(RecoveryAction::ResetToRequested)));
+        assert_eq!(file.download_state, DownloadState::Requested);
+        assert!(part_path.is_file() && progress_path.is_file());
+    }
+
+    #[test]
+    fn removes_partial_download_without_progress_file() {
+        let (_temporary_directory, download_directory) = download_directory();
+        let (part_path, _) = partial_download_paths(&download_directory, URL);
+        std::fs::write(&part_path, b"partial").unwrap();
+
+        let mut file = document(DownloadState::Running(download_directory.clone()));
+        let action = recover_interrupted_file(&mut file, &download_directory, false).unwrap();
+        assert!(matches!(action, Some(RecoveryAction::ResetToRequested)));
+        assert_eq!(file.download_state, DownloadState::Requested);
+        assert!(!part_path.exists());
+    }
+
+    #[test]
+    fn finishes_complete_download() {
+        for needs_postprocessing in [false, true] {
+            let (_temporary_directory, download_directory) = download_directory();
+            let path = download_directory.join("sheet.pdf");
+            std::fs::write(&path, b"complete").unwrap();
+
+            let mut file = document(DownloadState::Running(path.clone()));
+            let action = recover_interrupted_file(&mut file, &download_directory, needs_postprocessing).unwrap();
+            assert!(matches!(action, Some(RecoveryAction::Finished(ref finished_path)) if *finished_path == path));
+            let expected_state = if needs_postprocessing { DownloadState::PostprocessingPending(path.clone()) }
+                else { DownloadState::Completed(path.clone()) };
+            assert_eq!(file.download_state, expected_state);
+            assert!(file.download_time.is_some());
+            assert!(path.is_file());
+        }
+    }
+
+    #[test]
+    fn requests_file_missing_before_postprocessing_again() {
+        let (_temporary_directory, download_directory) = download_directory();
+        let mut file = document(DownloadState::PostprocessingPending(download_directory.join("sheet.pdf")));
+        let action = recover_interrupted_file(&mut file, &download_directory, true).unwrap();
+        assert!(matches!(action, Some(RecoveryAction::ResetToRequested)));
+        assert_eq!(file.download_state, DownloadState::Requested);
+    }
+
+    #[test]
+    fn leaves_other_states_alone() {
+        let (_temporary_directory, download_directory) = download_directory();
+        let mut file = document(DownloadState::Requested);
+        assert!(recover_interrupted_file(&mut file, &download_directory, false).unwrap().is_none());
+        assert_eq!(file.download_state, DownloadState::Requested);
+    }
+}
@@ -0,0 +1,111 @@
+use std::convert::TryFrom;
+
+use crate::{GenericResult, data::{Course, CourseFile, CourseFileDownload, DownloadState}};
+
+/// Indices of the downloaded videos of `course` that exceed its retention limits: videos older than
+/// `max_keep_days_videos` days, and videos beyond the newest `max_keep_videos`. The age of a video is
+/// measured from its download time (or its discovery time, if the download time is unknown).
+/// Only automatically requested videos are considered, videos the user requested are kept.
+pub fn videos_to_delete(course: &Course, now: chrono::DateTime<chrono::Utc>) -> Vec<usize> {
+    let mut downloaded_videos = course.files.iter().enumerate()
+        .filter(|(_, file)| file.auto_requested && file.file.is_video() && matches!(file.download_state, DownloadState::Completed(_)))
+        .map(|(i, file)| (i, file.download_time.unwrap_or(file.discovery_time)))
+        .collect::<Vec<_>>();
+    // Newest first
+    downloaded_videos.sort_by(|(_, time_a), (_, time_b)| time_b.cmp(time_a));
+
+    // Negative limits are treated as if there was no limit
+    let max_keep_days = course.max_keep_days_videos.filter(|days| *days >= 0);
+    let max_keep_videos = course.max_keep_videos.and_then(|count| usize::try_from(count).ok());
+    downloaded_videos.into_iter().enumerate()
+        .filter(|(rank, (_, time))| {
+            max_keep_videos.is_some_and(|max_keep_videos| *rank >= max_keep_videos)
+                || max_keep_days.is_some_and(|days| now - *time > chrono::Duration::days(days as i64))
+        })
+        .map(|(_, (i, _))| i)
+        .collect()
+}
+
+/// Deletes the downloaded file and its previous versions, and sets the download state to `Deleted`.
+/// Files that are already gone (e.g. removed by the user) are skipped.
+pub fn delete_download(file: &mut CourseFileDownload<CourseFile>) -> GenericResult<()> {
+    if let DownloadState::Completed(path) = &file.download_state {
+        for path in std::iter::once(path).chain(file.previous_versions.iter()) {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+    file.previous_versions.clear();
+    file.download_state = DownloadState::Deleted;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{CourseFileMetadata, CourseFileResource};
+
+    fn course(max_keep_days_videos: Option<i32>, max_keep_videos: Option<i32>) -> Course {
+        let mut course: Course = serde_json::from_str(r#"{
+            "url": "https://live.rbg.tum.de/course/2023/S/ana", "name": "Analysis", "course_type": "TumLive",
+            "video_download_directory": "videos", "file_download_directory": "files", "auto_download_mode": "Videos",
+            "files": [], "max_keep_days_videos": null, "max_keep_videos": null, "video_post_processing_steps": []
+        }"#).unwrap();
+        course.max_keep_days_videos = max_keep_days_videos;
+        course.max_keep_videos = max_keep_videos;
+        course
+    }
+
+    fn video(name: &str, download_time: chrono::DateTime<chrono::Utc>, auto_requested: bool) -> CourseFileDownload<CourseFile> {
+        CourseFileDownload {
+            file: CourseFile {
+                resource: CourseFileResource::HlsStream { main_m3u8_url: format!("https://live.rbg.tum.de/w/ana/{}", name) },
+                metadata: CourseFileMetadata::TumLiveStream { lecture_title: "Analysis".to_owned(), video_title: name.to_owned(),
+                    date_time_string: String::new() }
+            },
+            available: true,
+            download_state: DownloadState::Completed(format!("videos/{}.mp4", name).into()),
+            discovery_time: download_time,
+            download_time: Some(download_time),
+            auto_requested,
+            hls_variant: None,
+            validators: None,
+            previous_versions: vec![],
+            replaced_path: None,
+            failure: None
+        }
+    }
+
+    #[test]
+    fn deletes_videos_beyond_the_newest_ones() {
+        let now = chrono::Utc::now();
+        let mut course = course(None, Some(2));
+        course.files = (0..4).map(|i| video(&i.to_string(), now - chrono::Duration::days(i), true)).collect();
+        let mut to_delete = videos_to_delete(&course, now);
+        to_delete.sort();
+        assert_eq!(to_delete, vec![2, 3]);
+    }
+
+    #[test]
+    fn deletes_videos_older_than_max_keep_days() {
+        let now = chrono::Utc::now();
+        let mut course = course(Some(7), None);
+        course.files = vec![video("new", now - chrono::Duration::days(3), true), video("old", now - chrono::Duration::days(10), true)];
+        assert_eq!(videos_to_delete(&course, now), vec![1]);
+        // Negative limits mean no limit
+        course.max_keep_days_videos = Some(-1);
+        assert!(videos_to_delete(&course, now).is_empty());
+    }
+
+    #[test]
+    fn keeps_videos_the_user_requested() {
+        let now = chrono::Utc::now();
+        let mut course = course(Some(7), Some(0));
+        let mut not_completed = video("requested", now - chrono::Duration::days(30), true);
+        not_completed.download_state = DownloadState::Requested;
+        course.files = vec![video("manual", now - chrono::Duration::days(30), false), not_completed,
+            video("auto", now - chrono::Duration::days(30), true)];
+        assert_eq!(videos_to_delete(&course, now), vec![2]);
+    }
+}
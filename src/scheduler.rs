@@ -0,0 +1,141 @@
+use std::{collections::HashMap, pin::Pin, sync::Arc};
+use futures::{Future, Stream, stream::FuturesUnordered};
+use reqwest::Url;
+use tokio::sync::Semaphore;
+
+use crate::{GenericResult, download::DownloadInfo};
+
+pub type DownloadFuture<'a> = Pin<Box<dyn Future<Output = GenericResult<DownloadInfo>> + 'a>>;
+
+/// Runs downloads concurrently, with at most `max_parallel_downloads` downloads in total and at most
+/// `max_parallel_downloads_per_host` downloads from the same host running at the same time.
+/// Each download is identified by a key of type `K` (e.g. course and file indices), which is returned with its result.
+pub struct DownloadScheduler<'a, K> {
+    max_parallel_downloads: usize,
+    max_parallel_downloads_per_host: usize,
+    downloads: Vec<(K, String, DownloadFuture<'a>)>
+}
+
+impl<'a, K> DownloadScheduler<'a, K> {
+    pub fn new(max_parallel_downloads: usize, max_parallel_downloads_per_host: usize) -> Self {
+        DownloadScheduler {
+            max_parallel_downloads: max_parallel_downloads.max(1),
+            max_parallel_downloads_per_host: max_parallel_downloads_per_host.max(1),
+            downloads: vec![]
+        }
+    }
+
+    /// Adds a download of `url`. The future is not polled before `run` is called and a slot is available.
+    pub fn push(&mut self, key: K, url: &str, download: DownloadFuture<'a>) {
+        let host = Url::parse(url).ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+            .unwrap_or_default();
+        self.downloads.push((key, host, download));
+    }
+
+    /// Runs all downloads, yielding their results in the order in which they finish.
+    /// The downloads only make progress while the returned stream is polled.
+    pub fn run(self) -> impl Stream<Item = (K, GenericResult<DownloadInfo>)> + Unpin + 'a
+        where K: 'a
+    {
+        let global_slots = Arc::new(Semaphore::new(self.max_parallel_downloads));
+        let mut host_slots: HashMap<String, Arc<Semaphore>> = HashMap::new();
+        let max_parallel_downloads_per_host = self.max_parallel_downloads_per_host;
+
+        let running_downloads = FuturesUnordered::new();
+        for (key, host, download) in self.downloads {
+            let host_slots = host_slots.entry(host)
+                .or_insert_with(|| Arc::new(Semaphore::new(max_parallel_downloads_per_host)))
+                .clone();
+            let global_slots = global_slots.clone();
+            running_downloads.push(async move {
+                // Wait for a host slot first, s.t. no global slot is blocked while waiting for a busy host
+                let _host_permit = host_slots.acquire_owned().await.expect("Semaphore is never closed");
+                let _global_permit = global_slots.acquire_owned().await.expect("Semaphore is never closed");
+                (key, download.await)
+            });
+        }
+        running_downloads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, path::PathBuf, rc::Rc, time::Duration};
+    use futures::StreamExt;
+    use simple_error::simple_error;
+
+    // Counts the downloads that are running at the same time, in total and per host
+    #[derive(Default)]
+    struct Concurrency {
+        running: Cell<usize>,
+        max_running: Cell<usize>,
+        running_per_host: [Cell<usize>; 2],
+        max_running_per_host: [Cell<usize>; 2]
+    }
+
+    fn download<'a>(concurrency: &Rc<Concurrency>, key: usize, host: usize, duration_ms: u64) -> DownloadFuture<'a> {
+        let concurrency = concurrency.clone();
+        Box::pin(async move {
+            let increment = |running: &Cell<usize>, max_running: &Cell<usize>| {
+                running.set(running.get() + 1);
+                max_running.set(max_running.get().max(running.get()));
+            };
+            increment(&concurrency.running, &concurrency.max_running);
+            increment(&concurrency.running_per_host[host], &concurrency.max_running_per_host[host]);
+            tokio::time::sleep(Duration::from_millis(duration_ms)).await;
+            concurrency.running.set(concurrency.running.get() - 1);
+            concurrency.running_per_host[host].set(concurrency.running_per_host[host].get() - 1);
+            if key == 5 {
+                return Err(simple_error!("download {} failed", key).into());
+            }
+            Ok(DownloadInfo { final_path: Some(PathBuf::from(key.to_string())), ..DownloadInfo::default() })
+        })
+    }
+
+    #[tokio::test]
+    async fn results_are_attributed_to_their_keys_within_limits() {
+        let concurrency = Rc::new(Concurrency::default());
+        let hosts = ["https://a.example.com/file", "https://b.example.com/file"];
+        let mut scheduler = DownloadScheduler::new(3, 2);
+        // Downloads pushed first take longest, s.t. they finish out of order
+        for key in 0..8 {
+            let host = key % 2;
+            scheduler.push((key, host), hosts[host], download(&concurrency, key, host, 10 * (8 - key as u64)));
+        }
+        let results = scheduler.run().collect::<Vec<_>>().await;
+
+        let finish_order = results.iter().map(|((key, _), _)| *key).collect::<Vec<_>>();
+        assert_ne!(finish_order, (0..8).collect::<Vec<_>>());
+        let mut sorted_order = finish_order.clone();
+        sorted_order.sort_unstable();
+        assert_eq!(sorted_order, (0..8).collect::<Vec<_>>());
+        assert_eq!(results.iter().filter(|(_, result)| result.is_err()).count(), 1);
+        for ((key, _), result) in &results {
+            match result {
+                Ok(info) => assert_eq!(info.final_path, Some(PathBuf::from(key.to_string()))),
+                Err(error) => assert_eq!((*key, error.to_string()), (5, "download 5 failed".to_owned()))
+            }
+        }
+
+        assert_eq!(concurrency.max_running.get(), 3);
+        assert!(concurrency.max_running_per_host.iter().all(|max_running| max_running.get() <= 2));
+    }
+
+    #[tokio::test]
+    async fn a_busy_host_does_not_block_other_hosts() {
+        let concurrency = Rc::new(Concurrency::default());
+        let mut scheduler = DownloadScheduler::new(2, 1);
+        for key in 0..3 {
+            scheduler.push((key, 0), "https://a.example.com/file", download(&concurrency, key, 0, 20));
+        }
+        scheduler.push((3, 1), "https://b.example.com/file", download(&concurrency, 3, 1, 5));
+        let results = scheduler.run().collect::<Vec<_>>().await;
+
+        // The download from the other host runs alongside the first one and finishes before all others
+        assert_eq!(results[0].0, (3, 1));
+        assert_eq!(concurrency.max_running_per_host[0].get(), 1);
+        assert_eq!(concurrency.max_running.get(), 2);
+    }
+}
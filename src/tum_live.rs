@@ -1,11 +1,22 @@
-use reqwest;
+use reqwest::{self, Url};
 use std::sync::Arc;
-use select::{document::Document, predicate::{Predicate, Attr, Class, Name, Text}};
+use regex::Regex;
+use lazy_static::lazy_static;
+use select::{document::Document, node::Node, predicate::{Predicate, Attr, Class, Name, Text}};
 use simple_error::simple_error;
 use reqwest_cookie_store::CookieStoreMutex;
 
 use crate::{GenericResult, data::{CourseFile, CourseFileMetadata, CourseFileResource}};
 
+lazy_static! {
+    static ref M3U8_URL_REGEX: Regex = Regex::new(r#"https?://[^"'\s]+?\.m3u8[^"'\s]*"#).unwrap();
+}
+
+/// On a TUM Live course page, links to ongoing livestreams contain a badge with exactly this text
+const TUM_LIVE_LIVESTREAM_BADGE_TEXT: &str = "Live";
+/// Watch pages of TUM Live streams have paths like `/w/<course>/<stream id>`
+const TUM_LIVE_WATCH_PAGE_PATH_PREFIX: &str = "/w/";
+
 pub async fn tum_live_login(username: &str, password: &str) -> GenericResult<Arc<CookieStoreMutex>> {
     // Login needs to store cookies, so our client needs a cookie store
     let cookie_store = Arc::new(reqwest_cookie_store::CookieStoreMutex::default());
@@ -26,19 +37,24 @@ pub async fn tum_live_login(username: &str, password: &str) -> GenericResult<Arc
     }
 }
 
+/// Detects the recordings of a TUM Live course. The returned files point to the recordings' watch pages, since
+/// the HLS playlist urls found there are only valid for a limited time. Use `resolve_tum_live_playlist_url`
+/// to obtain the playlist when downloading.
 pub async fn detect_tum_live_videos(course_url: &str, tum_live_auth_cookies: Arc<CookieStoreMutex>) -> GenericResult<Vec<CourseFile>> {
     let client = reqwest::Client::builder()
         .cookie_provider(tum_live_auth_cookies.clone())
         .build()?;
     
-    let resp = client.get(course_url).send().await?;
+    let resp = client.get(course_url).send().await?.error_for_status()?;
+    let course_page_url = resp.url().clone();
     let course_page_dom = Document::from(resp.text().await?.as_str());
 
     let lecture_title = course_page_dom.find(Class("text-1")).next().map(|node| node.text().trim().to_owned()).unwrap_or_default();
 
     let recording_nodes = course_page_dom.find(Name("a").and(Class("text-3")).and(Attr("href", ())));
-    let course_videos = recording_nodes.map(|node| {
-        let video_url = node.attr("href").unwrap().to_owned();
+    let course_videos = recording_nodes.filter_map(|node| {
+        // Links on the course page are relative to it
+        let video_url = course_page_url.join(node.attr("href")?).ok()?.to_string();
         let video_title = node.text().trim().to_owned();
         let date_time_string = node.parent()
             .and_then(|parent| parent.parent())
@@ -47,8 +63,75 @@ pub async fn detect_tum_live_videos(course_url: &str, tum_live_auth_cookies: Arc
             .unwrap_or_default();
         let metadata = CourseFileMetadata::TumLiveStream {video_title, date_time_string, lecture_title: lecture_title.clone()};
         let resource = CourseFileResource::HlsStream {main_m3u8_url: video_url};
-        CourseFile {metadata, resource}
+        Some(CourseFile {metadata, resource})
     }).collect();
 
     Ok(course_videos)
 }
+
+/// Detects the livestreams currently running in a TUM Live course. Like the files of `detect_tum_live_videos`, the
+/// returned files point to the streams' watch pages, s.t. a recorded livestream is recognized once its recording
+/// is published. Each file comes with the stream's HLS playlist url, which is found on the watch page.
+pub async fn detect_tum_live_livestreams(course_url: &str, tum_live_auth_cookies: Arc<CookieStoreMutex>)
+    -> GenericResult<Vec<(CourseFile, String)>>
+{
+    let client = reqwest::Client::builder()
+        .cookie_provider(tum_live_auth_cookies.clone())
+        .build()?;
+
+    let resp = client.get(course_url).send().await?;
+    let course_page_url = resp.url().clone();
+    let (lecture_title, livestream_links) = {
+        let course_page_dom = Document::from(resp.text().await?.as_str());
+        let lecture_title = course_page_dom.find(Class("text-1")).next().map(|node| node.text().trim().to_owned()).unwrap_or_default();
+        // Other links (e.g. the "TUM-Live" logo) may contain the badge text too, but no badge and no watch page
+        let livestream_links = course_page_dom.find(Name("a").and(Attr("href", ())))
+            .filter_map(|node| {
+                let badge = livestream_badge(node)?;
+                let watch_page_url = course_page_url.join(node.attr("href")?).ok()
+                    .filter(|url| url.path().starts_with(TUM_LIVE_WATCH_PAGE_PATH_PREFIX))?;
+                Some((watch_page_url, text_without(node, badge)))
+            })
+            .collect::<Vec<_>>();
+        (lecture_title, livestream_links)
+    };
+
+    let mut livestreams = vec![];
+    for (watch_page_url, video_title) in livestream_links {
+        // The badge may be shown shortly before a stream starts or after it ended, without a playlist on the watch page
+        let m3u8_url = match resolve_tum_live_playlist_url(&client, watch_page_url.clone()).await {
+            Ok(m3u8_url) => m3u8_url,
+            Err(_) => continue
+        };
+        let date_time_string = chrono::Local::now().format("%Y-%m-%d %H:%M").to_string();
+        let metadata = CourseFileMetadata::TumLiveStream {video_title, date_time_string, lecture_title: lecture_title.clone()};
+        let resource = CourseFileResource::HlsStream {main_m3u8_url: watch_page_url.to_string()};
+        livestreams.push((CourseFile {metadata, resource}, m3u8_url));
+    }
+    Ok(livestreams)
+}
+
+// The element inside a link whose whole text is the livestream badge text
+fn livestream_badge(link_node: Node) -> Option<Node> {
+    link_node.descendants()
+        .filter(|node| node.index() != link_node.index() && node.name().is_some())
+        .find(|node| node.text().trim() == TUM_LIVE_LIVESTREAM_BADGE_TEXT)
+}
+
+// The text of `node`, leaving out the text inside `excluded_node`
+fn text_without(node: Node, excluded_node: Node) -> String {
+    node.descendants()
+        .filter(|descendant| !std::iter::successors(Some(*descendant), Node::parent).any(|ancestor| ancestor.index() == excluded_node.index()))
+        .filter_map(|descendant| descendant.as_text())
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Finds the HLS playlist url embedded in the player script of a TUM Live watch page
+pub async fn resolve_tum_live_playlist_url(client: &reqwest::Client, watch_page_url: Url) -> GenericResult<String> {
+    let watch_page = client.get(watch_page_url.clone()).send().await?.error_for_status()?.text().await?;
+    M3U8_URL_REGEX.find(&watch_page)
+        .map(|url_match| url_match.as_str().replace("\\/", "/"))
+        .ok_or(simple_error!("Could not find HLS playlist on TUM Live page {}", watch_page_url).into())
+}
@@ -0,0 +1,229 @@
+use reqwest::{self, Url, header};
+use std::{collections::{HashSet, VecDeque}, fmt::Display};
+use select::{document::Document, node::Node, predicate::{Attr, Name, Predicate}};
+use simple_error::simple_error;
+
+use crate::{GenericError, GenericResult, data::{CourseFile, CourseFileMetadata, WebsiteCrawlSettings},
+    http_headers::DEFAULT_HEADERS, moodle::{LinkTarget, classify_link}};
+
+#[derive(Debug)]
+pub struct WebsiteCrawlingError {
+    pub successful_detections: Vec<CourseFile>,
+    pub failed_detections: Vec<GenericError>
+}
+
+impl Display for WebsiteCrawlingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for WebsiteCrawlingError {}
+
+// One step of a link selector, like `div.content` or `#slides`
+struct SimpleSelector {
+    name: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>
+}
+
+impl SimpleSelector {
+    fn parse(selector: &str) -> GenericResult<Self> {
+        let is_identifier_char = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        let mut simple_selector = SimpleSelector { name: None, id: None, classes: vec![] };
+        let mut rest = selector;
+        while !rest.is_empty() {
+            let (prefix, after_prefix) = match rest.strip_prefix(['.', '#']) {
+                Some(after_prefix) => (rest.chars().next(), after_prefix),
+                None => (None, rest)
+            };
+            let end = after_prefix.find(|c| !is_identifier_char(c)).unwrap_or(after_prefix.len());
+            let identifier = &after_prefix[..end];
+            if identifier.is_empty() || (prefix.is_none() && simple_selector.name.is_some()) {
+                return Err(simple_error!("Unsupported link selector '{}'", selector).into());
+            }
+            match prefix {
+                Some('.') => simple_selector.classes.push(identifier.to_owned()),
+                Some(_) => simple_selector.id = Some(identifier.to_owned()),
+                None => simple_selector.name = Some(identifier.to_lowercase())
+            }
+            rest = &after_prefix[end..];
+        }
+        Ok(simple_selector)
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        self.name.as_ref().is_none_or(|name| node.name() == Some(name.as_str()))
+            && self.id.as_ref().is_none_or(|id| node.attr("id") == Some(id.as_str()))
+            && self.classes.iter().all(|class| node.attr("class")
+                .is_some_and(|classes| classes.split_whitespace().any(|c| c == class)))
+    }
+}
+
+// A link selector is a chain of simple selectors, each matching descendants of the previous one
+fn parse_link_selector(selector: &str) -> GenericResult<Vec<SimpleSelector>> {
+    let simple_selectors = selector.split_whitespace().map(SimpleSelector::parse).collect::<GenericResult<Vec<_>>>()?;
+    if simple_selectors.is_empty() {
+        return Err(simple_error!("Empty link selector").into());
+    }
+    Ok(simple_selectors)
+}
+
+fn select_nodes<'a>(document: &'a Document, selector: &[SimpleSelector]) -> Vec<Node<'a>> {
+    let mut nodes = document.find(|node: &Node| selector[0].matches(node)).collect::<Vec<_>>();
+    for simple_selector in &selector[1..] {
+        let mut seen_indices = HashSet::new();
+        nodes = nodes.iter()
+            .flat_map(|node| node.descendants().filter(|descendant| simple_selector.matches(descendant)))
+            .filter(|node| seen_indices.insert(node.index()))
+            .collect();
+    }
+    nodes
+}
+
+// Extracts the title of a page and the (absolute) urls and texts of its links
+fn extract_links(page: &str, page_url: &Url, selectors: &[Vec<SimpleSelector>]) -> (String, Vec<(Url, String)>) {
+    let page_dom = Document::from(page);
+    let page_title = page_dom.find(Name("title")).next()
+        .or_else(|| page_dom.find(Name("h1")).next())
+        .map(|node| node.text().split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| page_url.to_string());
+
+    let link_nodes = if selectors.is_empty() {
+        page_dom.find(Name("a").and(Attr("href", ()))).collect::<Vec<_>>()
+    } else {
+        // Matched elements may be links themselves, or contain links
+        let mut seen_indices = HashSet::new();
+        selectors.iter()
+            .flat_map(|selector| select_nodes(&page_dom, selector))
+            .flat_map(|node| std::iter::once(node).chain(node.descendants()))
+            .filter(|node| node.name() == Some("a") && node.attr("href").is_some())
+            .filter(|node| seen_indices.insert(node.index()))
+            .collect()
+    };
+
+    let links = link_nodes.into_iter().filter_map(|node| {
+        let mut url = page_url.join(node.attr("href")?).ok()?;
+        url.set_fragment(None);
+        let link_text = Some(node.text().split_whitespace().collect::<Vec<_>>().join(" "))
+            .filter(|text| !text.is_empty())
+            .or_else(|| node.attr("title").map(str::to_owned))
+            .unwrap_or_default();
+        Some((url, link_text))
+    }).collect();
+    (page_title, links)
+}
+
+/// Crawls the website at `course_url` for linked files. Links are classified by their file extension like on
+/// Moodle pages; HTML pages and links without file extension are crawled as subpages, up to `max_depth` levels deep.
+pub async fn detect_website_files(course_url: &str, settings: &WebsiteCrawlSettings, max_depth: i32)
+        -> GenericResult<Vec<CourseFile>>
+{
+    let selectors = settings.link_selectors.iter()
+        .map(|selector| parse_link_selector(selector))
+        .collect::<GenericResult<Vec<_>>>()?;
+    let client = reqwest::Client::builder()
+        .default_headers((*DEFAULT_HEADERS).clone())
+        .build()?;
+
+    let course_url = Url::parse(course_url)?;
+    let mut crawled_pages = HashSet::new();
+    crawled_pages.insert(course_url.clone());
+    let mut pages = VecDeque::new();
+    pages.push_back((course_url.clone(), 0));
+    let mut found_urls = HashSet::new();
+
+    let mut course_files = vec![];
+    let mut errors: Vec<GenericError> = vec![];
+    while let Some((page_url, depth)) = pages.pop_front() {
+        let resp = match client.get(page_url.clone()).send().await.and_then(reqwest::Response::error_for_status) {
+            Ok(resp) => resp,
+            Err(error) => { errors.push(error.into()); continue; }
+        };
+        // Links without file extension may turn out to be something else than a web page
+        let is_html = resp.headers().get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_none_or(|content_type| content_type.contains("html"));
+        if !is_html {
+            continue;
+        }
+        let page = match resp.text().await {
+            Ok(page) => page,
+            Err(error) => { errors.push(error.into()); continue; }
+        };
+
+        let (page_title, links) = extract_links(&page, &page_url, &selectors);
+        for (link_url, link_text) in links {
+            if !matches!(link_url.scheme(), "http" | "https")
+                || (!settings.follow_other_origins && link_url.origin() != course_url.origin()) {
+                continue;
+            }
+            match classify_link(link_url.to_string()) {
+                LinkTarget::Resource(resource) => {
+                    if found_urls.insert(resource.url().to_owned()) {
+                        let metadata = CourseFileMetadata::WebsiteLink { page_title: page_title.clone(), link_text };
+                        course_files.push(CourseFile { metadata, resource });
+                    }
+                },
+                LinkTarget::Subpage(_) | LinkTarget::WithoutExtension(_) => {
+                    if depth < max_depth && crawled_pages.insert(link_url.clone()) {
+                        pages.push_back((link_url, depth + 1));
+                    }
+                },
+                LinkTarget::Ignored => {}
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(course_files)
+    } else {
+        Err(WebsiteCrawlingError { successful_detections: course_files, failed_detections: errors }.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_simple_selectors() {
+        let selector = SimpleSelector::parse("DIV.content.main#slides").unwrap();
+        assert_eq!(selector.name.as_deref(), Some("div"));
+        assert_eq!(selector.id.as_deref(), Some("slides"));
+        assert_eq!(selector.classes, vec!["content", "main"]);
+
+        let selector = SimpleSelector::parse(".file-list").unwrap();
+        assert_eq!(selector.name, None);
+        assert_eq!(selector.classes, vec!["file-list"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_selectors() {
+        for selector in [".", "a[href]", "div>a", "a:hover", "div#"] {
+            assert!(SimpleSelector::parse(selector).is_err(), "'{}' should be rejected", selector);
+        }
+        assert!(parse_link_selector("  ").is_err());
+        assert_eq!(parse_link_selector("div.content  ul a").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn extracts_links_inside_selected_elements() {
+        let page = r#"<html><head><title>Lecture  page</title></head><body>
+            <nav><a href="/">Home</a></nav>
+            <div class="content"><ul><li><a href="slides/01.pdf#page=2">Slides 1</a></li></ul>
+            <a href="https://other.example.com/notes.pdf" title="Notes"></a></div>
+            <a id="extra" href="extra.zip">Extra</a>
+        </body></html>"#;
+        let page_url = Url::parse("https://example.com/lecture/index.html").unwrap();
+        let selectors = vec![parse_link_selector("div.content").unwrap(), parse_link_selector("#extra").unwrap()];
+        let (page_title, links) = extract_links(page, &page_url, &selectors);
+        assert_eq!(page_title, "Lecture page");
+        assert_eq!(links, vec![
+            (Url::parse("https://example.com/lecture/slides/01.pdf").unwrap(), "Slides 1".to_owned()),
+            (Url::parse("https://other.example.com/notes.pdf").unwrap(), "Notes".to_owned()),
+            (Url::parse("https://example.com/lecture/extra.zip").unwrap(), "Extra".to_owned())
+        ]);
+    }
+}